@@ -0,0 +1,100 @@
+/// Manage the registered nicknames of a gameserver and send it admin commands
+extern crate rustc_serialize;
+extern crate docopt;
+extern crate shotgun_common;
+
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::TcpStream;
+use std::path::Path;
+use std::process::exit;
+
+use shotgun_common::admin::Command;
+use shotgun_common::auth::Registry;
+
+#[derive(Debug,RustcDecodable)]
+struct Args {
+    cmd_register: bool,
+    cmd_unregister: bool,
+    cmd_send: bool,
+    arg_nickname: String,
+    arg_token: String,
+    arg_command: String,
+    flag_registry: String,
+    flag_server: String,
+    flag_token: String,
+}
+
+static USAGE: &str = "
+Shotgun Admin
+
+Usage:
+  shotgun_admin register <nickname> <token> [--registry=<FILE>]
+  shotgun_admin unregister <nickname> [--registry=<FILE>]
+  shotgun_admin send <command> --token=<TOKEN> [--server=<ADDR>]
+  shotgun_admin (-h | --help)
+
+Options:
+    --registry=<FILE>  The file passed to the gameserver with --registry [default: registry.txt]
+    --token=<TOKEN>    The --admin-token of the gameserver
+    --server=<ADDR>    The --admin-port of the gameserver [default: [::1]:6002]
+
+A command is one line like `ListConnections` or `Kick { nickname: \"coward_bot\" }`, see the README.
+";
+
+fn main() {
+    let args: Args = docopt::Docopt::new(USAGE).and_then(|d| d.decode())
+                                       .unwrap_or_else(|e| e.exit());
+
+    if args.cmd_send {
+        match send(&args) {
+            Ok(reply) => println!("{}", reply),
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(1)
+            }
+        }
+        return
+    }
+
+    let path = Path::new(&args.flag_registry);
+    let mut registry = Registry::load(path).unwrap_or_else(|e| {
+        eprintln!("can not read {:?}: {}", path, e);
+        exit(1)
+    });
+
+    if args.cmd_register {
+        registry.register(&args.arg_nickname, &args.arg_token);
+        println!("registered {:?}", args.arg_nickname);
+    } else if args.cmd_unregister && !registry.unregister(&args.arg_nickname) {
+        eprintln!("{:?} is not registered", args.arg_nickname);
+        exit(1)
+    }
+
+    registry.save(path).unwrap_or_else(|e| {
+        eprintln!("can not write {:?}: {}", path, e);
+        exit(1)
+    });
+}
+
+/// Authenticate and send one command, returns the JSON reply
+fn send(args: &Args) -> io::Result<String> {
+    let command: Command = args.arg_command.parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid command: {}", e)))?;
+    let stream = TcpStream::connect(&*args.flag_server)?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut reply = String::new();
+    for command in [Command::Authenticate { token: args.flag_token.clone() }, command] {
+        writeln!(writer, "{:?}", command)?;
+        reply.clear();
+        if reader.read_line(&mut reply)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the gameserver closed the connection"))
+        }
+        if reply.starts_with("{\"error\"") {
+            return Err(io::Error::other(reply.trim_end().to_string()))
+        }
+    }
+    Ok(reply.trim_end().to_string())
+}
@@ -1,131 +1,138 @@
 /// Connect to the server and play the game with the most defensive strategy
 extern crate rustc_serialize;
 extern crate docopt;
-extern crate futures;
-extern crate tokio_core;
-extern crate tokio_proto;
-extern crate tokio_service;
+extern crate tokio;
 extern crate shotgun_common;
+#[macro_use] extern crate tracing;
 // #[macro_use] extern crate lazy_static;
 
 
 use std::io;
-use std::net::SocketAddr;
 use std::net::ToSocketAddrs;
-use std::sync::Arc;
+use std::path::Path;
 
-use futures::Future;
-use tokio_core::net::TcpStream;
-use tokio_core::reactor::{Core, Handle};
-use tokio_proto::TcpClient;
-use tokio_proto::pipeline::{ClientProto, ClientService};
-use tokio_service::{Service, NewService};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tracing::Instrument;
 
 use shotgun_common::*;
 use shotgun_common::ParsedLine::*;
 use shotgun_common::Action::*;
+use shotgun_common::RoundAction::*;
 use shotgun_common::networking::*;
+use shotgun_common::logging;
+use shotgun_common::tls;
 
 #[derive(Debug,RustcDecodable)]
 struct Args {
     flag_port  : u16,
     flag_target: String,
     flag_nickname: String,
+    flag_token: Option<String>,
+    flag_tls: bool,
+    flag_tls_ca: Option<String>,
+    flag_log_level: String,
+    flag_log_json: bool,
 }
 
-static USAGE: &'static str = "
+static USAGE: &str = "
 Shotgun ClientBot
 
 Usage: 
-  shotgun_coward_bot [--target=<IP>] [--port=<PORT>] [--nickname=<NAME>]
+  shotgun_coward_bot [--target=<HOST>] [--port=<PORT>] [--nickname=<NAME>] [--token=<TOKEN>] [--tls [--tls-ca=<FILE>]] [--log-level=<LEVEL>] [--log-json]
   shotgun_coward_bot (-h | --help)
 
 Options:
     --port=<PORT>      The port to listen on [default: 6000]
-    --target=<IP>      The socket address to connect to [default: ::1]
+    --target=<HOST>    The host name or IP address to connect to [default: ::1]
     --nickname=<NAME>  The nickname of this instance [default: \"coward_bot\"]
+    --token=<TOKEN>    Required if the nickname is registered on the server
+    --tls              Connect with TLS, the server certificate must be valid for --target
+    --tls-ca=<FILE>    Trust this PEM certificate instead of the well known CAs, e.g. a self-signed one
+    --log-level=<LEVEL>  error, warn, info, debug or trace [default: debug]
+    --log-json         Log one JSON object per line instead of plain text
 ";
 
 
 
-fn main() {
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
     // allways print backtrace
     std::env::set_var("RUST_BACKTRACE", "1");
 
     let args: Args = docopt::Docopt::new(USAGE).and_then(|d| d.decode())
                                        .unwrap_or_else(|e| e.exit());
-    println!("args: {:?}",args);
-
-    let touple = format!("[{}]:{}", args.flag_target, args.flag_port);
-
-    let mut core = Core::new().unwrap();
-    let handle = core.handle();
-    let addr = (&*touple).to_socket_addrs().unwrap().next().unwrap();
-
-    let client = Client::connect(&addr, &handle)
-            .and_then(|client| {
-                client.call(ClientHello {
-                    nickname: args.flag_nickname.clone(),
-                    programming_language: "rust".into(),
-                })
-                //.for_each(|msg| {})
-                .and_then(move |response| {
-                    println!(" <-0 {:?}", response);
-                    let request = RequestNewGame;
-                    println!(" ->0 {:?}", request);
-                    client.call(request)
-                    .and_then(move |response| {
-                        println!(" <-1 {:?}", response);
-                        let request = response.answer(Load);
-                        println!(" ->1 {:?}", request);
-                        client.call(request)
-                        .and_then(move |response| {
-                            println!(" <-2 {:?}", response);
-                            let request = response.answer(Shoot);
-                            println!(" ->2 {:?}", request);
-                            client.call(request)
-                        })
-                    })
-                })
-            });
-
-
-    //let (_socket, data) =
-    let result = core.run(client);
-    println!("\n  Client result: {:?}", result);
-    //println!("{}", String::from_utf8_lossy(&data));
-}
+    logging::init(&args.flag_log_level, args.flag_log_json).unwrap_or_else(|e| panic!("--log-level: {}", e));
+    debug!(?args, "starting");
+    let span = info_span!("connection", nickname = %args.flag_nickname);
 
-struct Client {
-    inner: ClientService<TcpStream, LineProto>,
+    match connect(&args).instrument(span.clone()).await {
+        Ok(response) => info!(parent: &span, ?response, "done"),
+        Err(e) => error!(parent: &span, error = %e, "connection failed"),
+    }
 }
 
-impl Client {
-    /// Establish a connection to a multiplexed line-based server at the
-    /// provided `addr`.
-    pub fn connect(addr: &SocketAddr, handle: &Handle) -> Box<Future<Item = Client, Error = io::Error>> {
-        let ret = TcpClient::new(LineProto::new())
-            .connect(addr, handle)
-            .map(move |client_service| {
-                //let validate = Validate { inner: client_service};
-                //Client { inner: validate }
-                Client {
-                    inner: client_service,
-                }
-            });
-
-        Box::new(ret)
+/// Connect to the server, encrypted with `--tls`, and play one game
+async fn connect(args: &Args) -> io::Result<ParsedLine> {
+    // A host name or an IP address, the same target names the server for TLS
+    let target = (args.flag_target.as_str(), args.flag_port);
+    let addr = target.to_socket_addrs()?.next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{}:{}", target.0, target.1)))?;
+    let socket = TcpStream::connect(addr).await?;
+
+    if args.flag_tls {
+        let ca = args.flag_tls_ca.as_ref().map(Path::new);
+        let stream = tls::connect(tls::client_config(ca)?, &args.flag_target, socket).await?;
+        play(Client::new(stream), args).await
+    } else {
+        play(Client::new(socket), args).await
     }
 }
 
-impl Service for Client {
-    type Request = ParsedLine;
-    type Response = ParsedLine;
-    type Error = io::Error;
-    // For simplicity, box the future.
-    type Future = Box<Future<Item = ParsedLine, Error = io::Error>>;
-
-    fn call(&self, req: ParsedLine) -> Self::Future {
-        Box::new( self.inner.call(req) )
+/// Load, shoot once, then duck until the game is over
+async fn play<T: AsyncRead + AsyncWrite + Unpin>(mut client: Client<T>, args: &Args) -> io::Result<ParsedLine> {
+    client.call(ClientHello {
+        nickname: args.flag_nickname.clone(),
+        programming_language: "rust".into(),
+        token: args.flag_token.clone(),
+    }).await?;
+    let mut line = client.call(RequestNewGame).await?;
+    let mut moves = vec![Load, Shoot].into_iter();
+    let mut game = None;
+    loop {
+        match line {
+            // The outcome is pushed after the last `RoundResult`
+            MultiplexedMessage { action: WinGame, .. } | MultiplexedMessage { action: LoseGame, .. } | MultiplexedMessage { action: ErrorEnd, .. } => return Ok(line),
+            // A refused move, or why the game ends with the `ErrorEnd` that follows
+            MultiplexedMessage { action: Action::Error { code, ref message }, .. } => {
+                warn!(%code, %message, "error from the server");
+                line = client.receive().await?;
+            }
+            // The server was reconfigured, the next round will be timed differently
+            ServerHello { max_round_length, .. } => {
+                info!(?max_round_length, "new round length");
+                line = client.receive().await?;
+            }
+            Announcement { ref message } => {
+                info!(%message, "announcement");
+                line = client.receive().await?;
+            }
+            MultiplexedMessage { game_id, action: NewGame { ref player_name_a, ref player_name_b } } => {
+                game = Some(GameState::new(game_id, vec![player_name_a.clone(), player_name_b.clone()]));
+                line = client.call(line.answer(PlayerInput(moves.next().unwrap_or(Duck)))).await?;
+            }
+            MultiplexedMessage { action: RoundResult { ref a, ref b }, .. } => {
+                let game = game.as_mut().ok_or_else(|| io::Error::other("RoundResult before NewGame"))?;
+                game.follow_duel(a.clone(), b.clone());
+                // After the last round there is no move to make, only the outcome to wait for
+                line = match game.is_over() {
+                    true => client.receive().await?,
+                    false => client.call(line.answer(PlayerInput(moves.next().unwrap_or(Duck)))).await?,
+                };
+            }
+            MultiplexedMessage { .. } => line = client.call(line.answer(PlayerInput(moves.next().unwrap_or(Duck)))).await?,
+            ParsedLine::Error { code, message } => return Err(io::Error::other(format!("disconnected with {}: {}", code, message))),
+            other => return Err(io::Error::other(format!("unexpected {:?}", other))),
+        }
     }
 }
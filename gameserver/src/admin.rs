@@ -0,0 +1,241 @@
+//! The admin channel on `--admin-port`, one `shotgun_common::admin::Command` per line
+//!
+//! Every command is answered with one line of JSON. Nothing but `Authenticate` is accepted
+//! before the token matched, a wrong token closes the connection.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+
+use rustc_serialize::json::{Json, ToJson};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+
+use shotgun_common::*;
+use shotgun_common::admin::Command;
+use crate::api;
+use crate::{ArenaServer, Settings, HOUSE_BOT_PREFIX};
+
+pub struct Admin {
+    pub token: String,
+    /// Reads the settings again, like on SIGHUP
+    pub configure: Box<dyn Fn() -> Result<Settings, String> + Send + Sync>,
+}
+
+/// Answer the commands of one admin connection until it closes
+pub async fn serve<T>(io: T, server: Arc<ArenaServer>, admin: Arc<Admin>) -> io::Result<()>
+    where T: AsyncRead + AsyncWrite
+{
+    let (reader, mut writer) = tokio::io::split(io);
+    let mut lines = BufReader::new(reader).lines();
+    let mut authenticated = false;
+    while let Some(line) = lines.next_line().await? {
+        let (reply, close) = match line.parse() {
+            Ok(Command::Authenticate { token }) if token == admin.token => {
+                info!("admin authenticated");
+                authenticated = true;
+                (ok(), false)
+            }
+            Ok(Command::Authenticate { .. }) => {
+                warn!("admin authentication failed");
+                (error("wrong token"), true)
+            }
+            Ok(command) if authenticated => (execute(&server, &admin, command).unwrap_or_else(|e| error(&e)), false),
+            Ok(_) => (error("authenticate first"), true),
+            Err(e) => (error(&e.to_string()), !authenticated),
+        };
+        writer.write_all(format!("{}\n", reply).as_bytes()).await?;
+        if close {
+            break
+        }
+    }
+    Ok(())
+}
+
+/// Carry out an authenticated command
+pub fn execute(server: &ArenaServer, admin: &Admin, command: Command) -> Result<Json, String> {
+    info!(?command, "admin command");
+    match command {
+        Command::Authenticate { .. } => Err("already authenticated".into()),
+        Command::ListConnections => Ok(connections(server)),
+        Command::ListArenas => Ok(Json::Array(server.live_games(|a| Some(api::game(a, true))))),
+        Command::Kick { nickname } => match server.kick(&nickname) {
+            true => Ok(ok()),
+            false => Err(format!("{:?} is neither connected nor playing", nickname)),
+        },
+        Command::Abort { game_id, message } => match server.abort(game_id, &message) {
+            true => Ok(ok()),
+            false => Err(format!("no running game {}", game_id)),
+        },
+        Command::ResetStatistics => {
+            server.reset_statistics();
+            Ok(ok())
+        }
+        Command::SetStatistics { nickname, won, lost } => {
+            server.set_statistics(&nickname, won, lost);
+            Ok(ok())
+        }
+        Command::StartTournament { games, players } => {
+            check_tournament(server, games, &players)?;
+            let matches = server.start_tournament(&players, games);
+            Ok(api::object(vec![("matches", matches.to_json())]))
+        }
+        Command::Broadcast { message } => {
+            server.broadcast(ParsedLine::Announcement { message });
+            Ok(ok())
+        }
+        Command::Reload => {
+            server.reload((admin.configure)()?);
+            Ok(ok())
+        }
+    }
+}
+
+fn check_tournament(server: &ArenaServer, games: u64, players: &[String]) -> Result<(), String> {
+    if server.is_draining() {
+        return Err("the server is shutting down".into())
+    }
+    if games < 1 {
+        return Err("a match needs at least one game".into())
+    }
+    if players.len() < 2 {
+        return Err("a tournament needs at least two players".into())
+    }
+    for (i, player) in players.iter().enumerate() {
+        if players[i + 1..].contains(player) {
+            return Err(format!("{:?} is listed twice", player))
+        }
+        if player.starts_with(HOUSE_BOT_PREFIX) {
+            return Err(format!("{:?} is a house bot", player))
+        }
+    }
+    Ok(())
+}
+
+/// Every connected player with its connection ids and running games
+fn connections(server: &ArenaServer) -> Json {
+    let mut games: HashMap<String, Vec<u64>> = HashMap::new();
+    let lineups = server.live_games(|a| Some((a.state.game_id, a.state.players.iter().map(|p| p.nickname.clone()).collect::<Vec<_>>())));
+    for (game_id, players) in lineups {
+        for nickname in players {
+            games.entry(nickname).or_default().push(game_id);
+        }
+    }
+    Json::Array(server.connected().into_iter()
+        .map(|(nickname, connections)| {
+            let games = games.remove(&nickname).unwrap_or_default();
+            api::object(vec![
+                ("nickname", nickname.to_json()),
+                ("connections", connections.to_json()),
+                ("games", games.to_json()),
+            ])
+        })
+        .collect())
+}
+
+fn ok() -> Json {
+    api::object(vec![("ok", true.to_json())])
+}
+
+fn error(message: &str) -> Json {
+    api::object(vec![("error", message.to_json())])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::mpsc;
+    use crate::OUTBOX_SIZE;
+    use shotgun_common::ParsedLine::*;
+    use tokio::io::AsyncReadExt;
+
+    fn admin() -> Admin {
+        Admin { token: "secret".into(), configure: Box::new(|| Ok(Settings::default())) }
+    }
+
+    fn duel(server: &ArenaServer) -> (u64, mpsc::Receiver<ParsedLine>, mpsc::Receiver<ParsedLine>) {
+        let (outbox, a) = mpsc::channel(OUTBOX_SIZE);
+        server.connect("a", 1, outbox);
+        let (outbox, b) = mpsc::channel(OUTBOX_SIZE);
+        server.connect("b", 2, outbox);
+        let _waiting = server.find_or_create_arena("a".into(), 2);
+        let _b = server.find_or_create_arena("b".into(), 2);
+        (server.live_games(|a| Some(a.state.game_id))[0], a, b)
+    }
+
+    #[test]
+    fn kick_closes_and_forfeits() {
+        let server = ArenaServer::new(Settings::default());
+        let (game_id, mut a, mut b) = duel(&server);
+        assert_eq!(Ok(ok()), execute(&server, &admin(), Command::Kick { nickname: "a".into() }));
+        assert_eq!(Ok(Error { code: ErrorCode::Kicked, message: "kicked by an admin".into() }), a.try_recv());
+        assert_eq!(Ok(MultiplexedMessage { game_id, action: Action::WinGame }), b.try_recv());
+        assert_eq!(Some(&(1, 0)), server.statistics().get("b"));
+        assert!(execute(&server, &admin(), Command::Kick { nickname: "nobody".into() }).is_err());
+    }
+
+    #[test]
+    fn abort_does_not_count() {
+        let server = ArenaServer::new(Settings::default());
+        let (game_id, mut a, _b) = duel(&server);
+        assert!(execute(&server, &admin(), Command::Abort { game_id: 999, message: "".into() }).is_err());
+        assert_eq!(Ok(ok()), execute(&server, &admin(), Command::Abort { game_id, message: "cheating".into() }));
+        assert_eq!(Ok(MultiplexedMessage { game_id, action: Action::ErrorEnd }), a.try_recv());
+        assert_eq!(0, server.live_games(|_| Some(())).len());
+        assert!(server.statistics().is_empty());
+    }
+
+    #[test]
+    fn statistics_and_tournament() {
+        let server = ArenaServer::new(Settings::default());
+        execute(&server, &admin(), Command::SetStatistics { nickname: "a".into(), won: 3, lost: 1 }).unwrap();
+        execute(&server, &admin(), Command::SetStatistics { nickname: "b".into(), won: 1, lost: 0 }).unwrap();
+        execute(&server, &admin(), Command::SetStatistics { nickname: "b".into(), won: 0, lost: 0 }).unwrap();
+        assert_eq!(vec![("a".to_string(), (3, 1))], server.statistics().into_iter().collect::<Vec<_>>());
+        execute(&server, &admin(), Command::ResetStatistics).unwrap();
+        assert!(server.statistics().is_empty());
+
+        let players = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        assert!(execute(&server, &admin(), Command::StartTournament { games: 3, players: vec!["a".into(), "a".into()] }).is_err());
+        let mut outboxes = Vec::new();
+        for (id, nickname) in players.iter().enumerate() {
+            let (outbox, pushed) = mpsc::channel(OUTBOX_SIZE);
+            server.connect(nickname, id, outbox);
+            outboxes.push(pushed);
+        }
+        let reply = execute(&server, &admin(), Command::StartTournament { games: 3, players }).unwrap();
+        assert_eq!(3, reply.find("matches").and_then(|m| m.as_array()).unwrap().len());
+        assert_eq!(0, server.live_games(|_| Some(())).len());
+        // Everybody gets the `NewMatch` of both its series first
+        for _ in 0..2 {
+            match server.take_pending("a") {
+                Some(MultiplexedMessage { action: Action::NewMatch { games: 3 }, .. }) => {},
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        assert_eq!(None, server.take_pending("a"));
+        // One series after the other, c waits until a is done with b
+        let _a = server.next_match_game("a").unwrap();
+        let _c = server.next_match_game("c").unwrap();
+        assert_eq!(0, server.live_games(|_| Some(())).len());
+        let _b = server.next_match_game("b").unwrap();
+        assert_eq!(vec![vec!["a".to_string(), "b".to_string()]], server.live_games(|a| Some(a.state.players.iter().map(|p| p.nickname.clone()).collect::<Vec<_>>())));
+    }
+
+    #[tokio::test]
+    async fn authenticate_first() {
+        let server = ArenaServer::new(Settings::default());
+        let (mut client, io) = tokio::io::duplex(4096);
+        tokio::spawn(serve(io, server.clone(), Arc::new(admin())));
+        client.write_all(b"Authenticate { token: \"secret\" }\nBroadcast { message: \"hi\" }\nAuthenticate { token: \"guess\" }\n").await.unwrap();
+        let mut replies = String::new();
+        client.read_to_string(&mut replies).await.unwrap();
+        assert_eq!("{\"ok\":true}\n{\"ok\":true}\n{\"error\":\"wrong token\"}\n", replies);
+
+        let (mut client, io) = tokio::io::duplex(4096);
+        tokio::spawn(serve(io, server, Arc::new(admin())));
+        client.write_all(b"ListConnections\nListConnections\n").await.unwrap();
+        let mut replies = String::new();
+        client.read_to_string(&mut replies).await.unwrap();
+        assert_eq!("{\"error\":\"authenticate first\"}\n", replies);
+    }
+}
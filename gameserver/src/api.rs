@@ -0,0 +1,237 @@
+//! Read-only HTTP API answering with JSON, for dashboards that do not speak the game protocol
+//!
+//! * `/players` the leaderboard
+//! * `/players/{nickname}` one player
+//! * `/games/live` all running games
+//! * `/games/finished` the recently finished games, newest first
+//! * `/games/{id}` a running or recently finished game
+//! * `/games/{id}/replay` every round of that game
+//! * `/metrics` counters for Prometheus, in its text format
+
+use std::collections::BTreeMap;
+
+use rustc_serialize::json::{Json, ToJson};
+
+use shotgun_common::*;
+use shotgun_common::http::{Request, Response};
+use crate::arena::Arena;
+use crate::dashboard;
+use crate::ArenaServer;
+
+/// Route a request, everything unknown is a 404
+pub fn handle(server: &ArenaServer, req: &Request) -> Response {
+    if req.method != "GET" {
+        return Response::new(405)
+    }
+    let path = req.path.split('?').next().unwrap_or("");
+    if let Some(response) = dashboard::asset(path) {
+        return response
+    }
+    if path == "/metrics" {
+        return Response::new(200).body("text/plain; version=0.0.4", server.metrics.render().into_bytes())
+    }
+    let segments: Vec<String> = path.split('/').filter(|s| !s.is_empty()).map(percent_decode).collect();
+    let segments: Vec<&str> = segments.iter().map(|s| s.as_str()).collect();
+
+    let json = match &*segments {
+        ["players"] => Some(players(server)),
+        ["players", nickname] => player(server, nickname),
+        ["games", "live"] => Some(Json::Array(server.live_games(|a| Some(game(a, true))))),
+        ["games", "finished"] => Some(server.with_finished_games(|arenas| Json::Array(arenas.into_iter().rev().map(|a| game(a, false)).collect()))),
+        ["games", id] => id.parse().ok().and_then(|id| server.with_game(id, game)),
+        ["games", id, "replay"] => id.parse().ok().and_then(|id| server.with_game(id, |arena, _| replay(arena))),
+        _ => None,
+    };
+
+    match json {
+        Some(json) => Response::new(200)
+            .header("Access-Control-Allow-Origin", "*")
+            .body("application/json", json.to_string().into_bytes()),
+        None => Response::new(404),
+    }
+}
+
+/// The leaderboard, most wins first
+pub fn players(server: &ArenaServer) -> Json {
+    let mut statistics: Vec<(String, (u64, u64))> = server.statistics().into_iter().collect();
+    statistics.sort_by(|a, b| (b.1).0.cmp(&(a.1).0).then_with(|| a.0.cmp(&b.0)));
+    Json::Array(statistics.into_iter()
+        .map(|(nickname, (won, lost))| object(vec![
+            ("nickname", nickname.to_json()),
+            ("won", won.to_json()),
+            ("lost", lost.to_json()),
+        ]))
+        .collect())
+}
+
+fn player(server: &ArenaServer, nickname: &str) -> Option<Json> {
+    let (won, lost) = server.statistics().get(nickname).cloned().unwrap_or((0, 0));
+    let connected = server.is_connected(nickname);
+    let games = server.live_games_of(nickname);
+    if won + lost == 0 && !connected && games.is_empty() {
+        return None
+    }
+    Some(object(vec![
+        ("nickname", nickname.to_json()),
+        ("won", won.to_json()),
+        ("lost", lost.to_json()),
+        ("connected", connected.to_json()),
+        ("games", games.to_json()),
+    ]))
+}
+
+pub fn game(arena: &Arena, live: bool) -> Json {
+    let state = &arena.state;
+    object(vec![
+        ("game_id", state.game_id.to_json()),
+        ("live", live.to_json()),
+        ("match_id", arena.match_id.to_json()),
+        ("rounds", arena.rounds.len().to_json()),
+        ("players", players_json(&state.players)),
+        ("winner", if state.is_over() { state.winner().map(|w| w.to_string()).to_json() } else { Json::Null }),
+    ])
+}
+
+/// Every round of a game
+pub fn replay(arena: &Arena) -> Json {
+    object(vec![
+        ("game_id", arena.state.game_id.to_json()),
+        ("players", arena.state.players.iter().map(|p| p.nickname.clone()).collect::<Vec<_>>().to_json()),
+        ("rounds", Json::Array(arena.rounds.iter()
+            .map(|round| object(vec![
+                ("moves", Json::Array(round.moves.iter().map(move_json).collect())),
+                ("players", players_json(&round.players)),
+            ]))
+            .collect())),
+    ])
+}
+
+fn players_json(players: &[PlayerState]) -> Json {
+    Json::Array(players.iter()
+        .map(|p| object(vec![
+            ("nickname", p.nickname.to_json()),
+            ("ammo_bag", p.ammo_bag.to_json()),
+            ("alive", p.alive.to_json()),
+            ("team", p.team.to_json()),
+        ]))
+        .collect())
+}
+
+fn move_json(mv: &PlayerMove) -> Json {
+    object(vec![
+        ("player", mv.player.to_json()),
+        ("action", format!("{:?}", mv.action).to_json()),
+        ("target", mv.target.to_json()),
+    ])
+}
+
+pub fn object(fields: Vec<(&str, Json)>) -> Json {
+    Json::Object(fields.into_iter().map(|(k, v)| (k.to_string(), v)).collect::<BTreeMap<_, _>>())
+}
+
+/// Nicknames may contain anything, so they arrive percent-encoded
+fn percent_decode(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            },
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            },
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Settings;
+
+    fn get(server: &ArenaServer, path: &str) -> (u16, String) {
+        let response = handle(server, &Request { method: "GET".into(), path: path.into(), headers: vec![] });
+        (response.status, String::from_utf8(response.body).unwrap())
+    }
+
+    fn finished_game(server: &ArenaServer) -> u64 {
+        let _waiting = server.find_or_create_arena("a".into(), 2);
+        let _bc = server.find_or_create_arena("b c".into(), 2);
+        let game_id = server.live_games(|a| Some(a.state.game_id))[0];
+        let mv = |player: &str, action| PlayerMove { player: player.into(), action, target: None };
+        let _a = server.play(game_id, mv("a", RoundAction::Load));
+        let _bc = server.play(game_id, mv("b c", RoundAction::Duck));
+        let _a = server.play(game_id, mv("a", RoundAction::Shoot));
+        let _bc = server.play(game_id, mv("b c", RoundAction::Load));
+        game_id
+    }
+
+    #[test]
+    fn leaderboard() {
+        let server = ArenaServer::new(Settings::default());
+        finished_game(&server);
+        assert_eq!((200, r#"[{"lost":0,"nickname":"a","won":1},{"lost":1,"nickname":"b c","won":0}]"#.to_string()), get(&server, "/players"));
+        assert_eq!(200, get(&server, "/players/b%20c").0);
+        assert_eq!(404, get(&server, "/players/nobody").0);
+    }
+
+    #[test]
+    fn live_and_finished_games() {
+        let server = ArenaServer::new(Settings::default());
+        assert_eq!((200, "[]".to_string()), get(&server, "/games/live"));
+        let game_id = finished_game(&server);
+        assert_eq!((200, "[]".to_string()), get(&server, "/games/live"));
+        assert!(get(&server, "/games/finished").1.contains(&format!(r#""game_id":{}"#, game_id)));
+
+        let (status, body) = get(&server, &format!("/games/{}", game_id));
+        assert_eq!(200, status);
+        assert!(body.contains(r#""live":false"#), "{}", body);
+        assert!(body.contains(r#""winner":"a""#), "{}", body);
+        assert_eq!(404, get(&server, "/games/4711").0);
+    }
+
+    #[test]
+    fn replay_has_every_round() {
+        let server = ArenaServer::new(Settings::default());
+        let game_id = finished_game(&server);
+        let (status, body) = get(&server, &format!("/games/{}/replay", game_id));
+        assert_eq!(200, status);
+        let replay = Json::from_str(&body).unwrap();
+        let rounds = replay.find("rounds").and_then(|r| r.as_array()).unwrap();
+        assert_eq!(2, rounds.len());
+        assert_eq!(Some("Shoot"), rounds[1].find_path(&["moves"]).and_then(|m| m[0].find("action")).and_then(|a| a.as_string()));
+    }
+
+    #[test]
+    fn metrics_count_games() {
+        let server = ArenaServer::new(Settings::default());
+        finished_game(&server);
+        let (status, body) = get(&server, "/metrics");
+        assert_eq!(200, status);
+        assert!(body.contains("shotgun_games_started_total 1\n"), "{}", body);
+        assert!(body.contains("shotgun_games_finished_total{outcome=\"win\"} 1\n"), "{}", body);
+        assert!(body.contains("shotgun_round_latency_seconds_count 4\n"), "{}", body);
+        assert!(body.contains("shotgun_queue_wait_seconds_count 2\n"), "{}", body);
+    }
+
+    #[test]
+    fn only_get() {
+        let server = ArenaServer::new(Settings::default());
+        let response = handle(&server, &Request { method: "POST".into(), path: "/players".into(), headers: vec![] });
+        assert_eq!(405, response.status);
+    }
+
+    #[test]
+    fn decode_nicknames() {
+        assert_eq!("b c", percent_decode("b%20c"));
+        assert_eq!("100%", percent_decode("100%"));
+    }
+}
@@ -0,0 +1,394 @@
+//! Games in progress and the rounds played in them
+
+use std::mem;
+use std::time::Instant;
+use futures::channel::oneshot;
+
+use shotgun_common::*;
+use shotgun_common::Action::*;
+use shotgun_common::RoundAction::*;
+use shotgun_common::strategy::Strategy;
+use crate::violation::Violation;
+
+/// A finished round, kept for replays
+pub struct Round {
+    /// As resolved by the rules engine
+    pub moves: Vec<PlayerMove>,
+    /// Everyone after the round
+    pub players: Vec<PlayerState>,
+}
+
+/// One running game
+pub struct Arena {
+    pub state: GameState,
+    /// Set if this game is part of a match series
+    pub match_id: Option<u64>,
+    /// Nickname and strategy of the built-in opponent, if it plays here
+    pub house_bot: Option<(String, Strategy)>,
+    /// Every round played so far
+    pub rounds: Vec<Round>,
+    /// When the current round started, for the latency of moves
+    pub round_started: Instant,
+    /// Moves received for the current round
+    moves: Vec<PlayerMove>,
+    /// Everyone waiting for the end of the current round
+    waiting: Vec<(String, oneshot::Sender<Action>)>,
+    /// Disconnected players, the round does not wait for them and counts their move as `Timeout`
+    absent: Vec<String>,
+    /// `WinGame` or `LoseGame` for the players the game is over for, pushed by the server
+    outcomes: Vec<(String, Action)>,
+}
+
+impl Arena {
+    pub fn new(state: GameState) -> Arena {
+        Arena {
+            state,
+            match_id: None,
+            house_bot: None,
+            rounds: Vec::new(),
+            round_started: Instant::now(),
+            moves: Vec::new(),
+            waiting: Vec::new(),
+            absent: Vec::new(),
+            outcomes: Vec::new(),
+        }
+    }
+
+    /// Two players play a classic game, everything bigger is free-for-all
+    pub fn is_duel(&self) -> bool {
+        self.state.players.len() == 2
+    }
+
+    /// The message starting the game
+    pub fn announcement(&self) -> Action {
+        let teams = self.state.teams();
+        if teams.len() == 2 {
+            return NewTeamGame {
+                team_a: self.state.team_members(teams[0]),
+                team_b: self.state.team_members(teams[1]),
+            }
+        }
+
+        let mut names: Vec<String> = self.state.players.iter().map(|p| p.nickname.clone()).collect();
+        if self.is_duel() {
+            let player_name_b = names.pop().unwrap();
+            let player_name_a = names.pop().unwrap();
+            NewGame { player_name_a, player_name_b }
+        } else {
+            NewArena { players: names }
+        }
+    }
+
+    /// Record a move for the current round
+    ///
+    /// The returned receiver resolves with the result as soon as every surviving player moved.
+    /// Moves of eliminated players and strangers, invalid targets and second moves in a round are refused.
+    pub fn submit(&mut self, mv: PlayerMove) -> Result<oneshot::Receiver<Action>, Violation> {
+        self.check(&mv)?;
+
+        let (tx, rx) = oneshot::channel();
+        self.waiting.push((mv.player.clone(), tx));
+        self.moves.push(mv);
+
+        // The house bot decides on what it knew at the start of the round
+        if let Some((ref name, strategy)) = self.house_bot {
+            let alive = self.state.player(name).map(|p| p.alive) == Some(true);
+            if alive && !self.moves.iter().any(|m| m.player == *name) {
+                self.moves.push(strategy.next_move(&self.state, name));
+            }
+        }
+
+        if self.round_complete() {
+            self.finish_round();
+        }
+        Ok(rx)
+    }
+
+    fn check(&self, mv: &PlayerMove) -> Result<(), Violation> {
+        let game_id = self.state.game_id;
+        match self.state.player(&mv.player) {
+            None => return Err(Violation::NotInGame(game_id)),
+            Some(p) if !p.alive => return Err(Violation::Eliminated(game_id)),
+            Some(_) => {},
+        }
+        match mv.action {
+            Load | Duck | Shoot => {},
+            ref action => return Err(Violation::InvalidMove(action.clone())),
+        }
+        if let Some(ref target) = mv.target {
+            if *target == mv.player || self.state.player(target).is_none() {
+                return Err(Violation::InvalidTarget(target.clone()))
+            }
+        }
+        if self.moves.iter().any(|m| m.player == mv.player) {
+            return Err(Violation::DuplicateMove(game_id))
+        }
+        Ok(())
+    }
+
+    /// Every surviving player moved or is absent, and somebody is still there
+    fn round_complete(&self) -> bool {
+        !self.moves.is_empty() && self.state.survivors().iter()
+            .all(|p| self.absent.contains(&p.nickname) || self.moves.iter().any(|m| m.player == p.nickname))
+    }
+
+    /// Nobody moved in the current round, although somebody present could have
+    pub fn is_silent(&self) -> bool {
+        self.moves.is_empty() && self.state.survivors().iter().any(|p| !self.absent.contains(&p.nickname))
+    }
+
+    /// End the current round, everyone who did not move is counted as `Timeout`
+    pub fn time_out(&mut self) {
+        if !self.moves.is_empty() {
+            self.finish_round();
+        }
+    }
+
+    /// Rounds go on without an absent player, which may finish the current round
+    pub fn set_absent(&mut self, nickname: &str, absent: bool) {
+        self.absent.retain(|n| n != nickname);
+        if !absent || self.state.player(nickname).is_none() {
+            return
+        }
+        self.absent.push(nickname.to_string());
+        if self.round_complete() {
+            self.finish_round();
+        }
+    }
+
+    /// The player gives up and is out of the game, which may end the current round or the game
+    pub fn forfeit(&mut self, nickname: &str) {
+        self.absent.retain(|n| n != nickname);
+        match self.state.players.iter_mut().find(|p| p.nickname == nickname) {
+            Some(player) => player.alive = false,
+            None => return,
+        }
+        self.moves.retain(|m| m.player != nickname);
+        self.waiting.retain(|w| w.0 != nickname);
+        // Whoever already moved hears how it ended
+        if self.round_complete() || (self.state.is_over() && !self.moves.is_empty()) {
+            self.finish_round();
+        } else if self.state.is_over() {
+            let survivors = self.state.survivors().iter().map(|p| p.nickname.clone()).collect();
+            self.game_over(survivors);
+        }
+    }
+
+    /// The outcomes since the last call, to push to the players
+    pub fn take_outcomes(&mut self) -> Vec<(String, Action)> {
+        mem::take(&mut self.outcomes)
+    }
+
+    /// End the game before it is over, everyone waiting for the round gets the `Error`,
+    /// followed by an `ErrorEnd` for everyone still in the game
+    pub fn abort(&mut self, code: ErrorCode, message: &str) {
+        self.moves.clear();
+        for (_, tx) in self.waiting.drain(..) {
+            let _ = tx.send(Error { code, message: message.into() });
+        }
+        self.abandon();
+    }
+
+    /// End the game without a winner, everyone still in it gets an `ErrorEnd`
+    pub fn abandon(&mut self) {
+        for p in self.state.survivors() {
+            self.outcomes.push((p.nickname.clone(), ErrorEnd));
+        }
+    }
+
+    fn finish_round(&mut self) {
+        let contenders: Vec<String> = self.state.survivors().iter().map(|p| p.nickname.clone()).collect();
+        let moves = mem::take(&mut self.moves);
+        let result = self.state.resolve_round(moves);
+        self.rounds.push(Round {
+            moves: result.clone(),
+            players: self.state.players.clone(),
+        });
+        self.round_started = Instant::now();
+
+        let report = if self.is_duel() {
+            let action_of = |i: usize| {
+                let name = &self.state.players[i].nickname;
+                result.iter().find(|m| m.player == *name).map(|m| m.action.clone()).unwrap_or(Timeout)
+            };
+            RoundResult { a: action_of(0), b: action_of(1) }
+        } else {
+            ArenaResult { moves: result.clone() }
+        };
+
+        for (_, tx) in self.waiting.drain(..) {
+            // The player may have disconnected in the meantime
+            let _ = tx.send(report.clone());
+        }
+
+        if self.state.is_over() {
+            self.game_over(contenders);
+        } else {
+            for nickname in contenders {
+                if self.state.player(&nickname).map(|p| p.alive) == Some(false) {
+                    self.outcomes.push((nickname, LoseGame));
+                }
+            }
+        }
+    }
+
+    fn game_over(&mut self, players: Vec<String>) {
+        for nickname in players {
+            let outcome = if self.state.has_won(&nickname) { WinGame } else { LoseGame };
+            self.outcomes.push((nickname, outcome));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    fn mv(player: &str, action: RoundAction, target: Option<&str>) -> PlayerMove {
+        PlayerMove { player: player.into(), action, target: target.map(|t| t.into()) }
+    }
+
+    #[test]
+    fn duel_announces_new_game() {
+        let arena = Arena::new(GameState::new(1, vec!["a".into(), "b".into()]));
+        assert_eq!(NewGame { player_name_a: "a".into(), player_name_b: "b".into() }, arena.announcement());
+    }
+
+    #[test]
+    fn team_game_announces_teams() {
+        let state = GameState::with_teams(1, vec![("a".into(), 3), ("b".into(), 4), ("c".into(), 3), ("d".into(), 4)], false);
+        let expected = NewTeamGame { team_a: vec!["a".into(), "c".into()], team_b: vec!["b".into(), "d".into()] };
+        assert_eq!(expected, Arena::new(state).announcement());
+    }
+
+    #[test]
+    fn round_waits_for_everyone() {
+        let mut arena = Arena::new(GameState::new(1, vec!["a".into(), "b".into(), "c".into()]));
+        let a = arena.submit(mv("a", Load, None)).unwrap();
+        assert_eq!(Some(Violation::DuplicateMove(1)), arena.submit(mv("a", Duck, None)).err());
+        let _b = arena.submit(mv("b", Duck, None)).unwrap();
+        let _c = arena.submit(mv("c", Load, None)).unwrap();
+        let expected = ArenaResult { moves: vec![mv("a", Load, None), mv("b", Duck, None), mv("c", Load, None)] };
+        assert_eq!(Ok(expected), block_on(a));
+    }
+
+    #[test]
+    fn house_bot_moves_along() {
+        let mut arena = Arena::new(GameState::new(1, vec!["a".into(), "house:gunslinger".into()]));
+        arena.house_bot = Some(("house:gunslinger".into(), Strategy::Gunslinger));
+        let a = arena.submit(mv("a", Duck, None)).unwrap();
+        assert_eq!(Ok(RoundResult { a: Duck, b: Load }), block_on(a));
+        let a = arena.submit(mv("a", Load, None)).unwrap();
+        assert_eq!(Ok(RoundResult { a: Load, b: Shoot }), block_on(a));
+        assert_eq!(vec![("a".to_string(), LoseGame), ("house:gunslinger".to_string(), WinGame)], arena.take_outcomes());
+    }
+
+    #[test]
+    fn absent_player_times_out() {
+        let mut arena = Arena::new(GameState::new(1, vec!["a".into(), "b".into()]));
+        let a = arena.submit(mv("a", Load, None)).unwrap();
+        arena.set_absent("b", true);
+        assert_eq!(Ok(RoundResult { a: Load, b: Timeout }), block_on(a));
+
+        arena.set_absent("b", false);
+        let a = arena.submit(mv("a", Duck, None)).unwrap();
+        let _b = arena.submit(mv("b", Load, None)).unwrap();
+        assert_eq!(Ok(RoundResult { a: Duck, b: Load }), block_on(a));
+    }
+
+    #[test]
+    fn silent_player_times_out() {
+        let mut arena = Arena::new(GameState::new(1, vec!["a".into(), "b".into()]));
+        assert!(arena.is_silent());
+        let a = arena.submit(mv("a", Load, None)).unwrap();
+        assert!(!arena.is_silent());
+        arena.time_out();
+        assert_eq!(Ok(RoundResult { a: Load, b: Timeout }), block_on(a));
+        assert!(arena.is_silent());
+    }
+
+    #[test]
+    fn absent_players_are_not_silent() {
+        let mut arena = Arena::new(GameState::new(1, vec!["a".into(), "b".into()]));
+        arena.set_absent("a", true);
+        assert!(arena.is_silent());
+        arena.set_absent("b", true);
+        assert!(!arena.is_silent());
+    }
+
+    #[test]
+    fn forfeit_ends_game() {
+        let mut arena = Arena::new(GameState::new(1, vec!["a".into(), "b".into(), "c".into()]));
+        let a = arena.submit(mv("a", Load, None)).unwrap();
+        arena.forfeit("b");
+        assert!(!arena.state.is_over());
+        arena.forfeit("c");
+        assert!(arena.state.is_over());
+        assert_eq!(Ok(ArenaResult { moves: vec![mv("a", Load, None)] }), block_on(a));
+        assert_eq!(vec![("a".to_string(), WinGame)], arena.take_outcomes());
+        assert_eq!(1, arena.rounds.len());
+    }
+
+    #[test]
+    fn forfeit_between_rounds_wins() {
+        let mut arena = Arena::new(GameState::new(1, vec!["a".into(), "b".into()]));
+        arena.forfeit("b");
+        assert_eq!(vec![("a".to_string(), WinGame)], arena.take_outcomes());
+        assert!(arena.rounds.is_empty());
+    }
+
+    #[test]
+    fn hit_player_loses_while_game_goes_on() {
+        let mut arena = Arena::new(GameState::new(1, vec!["a".into(), "b".into(), "c".into()]));
+        let _a = arena.submit(mv("a", Load, None)).unwrap();
+        let _b = arena.submit(mv("b", Load, None)).unwrap();
+        let _c = arena.submit(mv("c", Load, None)).unwrap();
+        let _a = arena.submit(mv("a", Shoot, Some("b"))).unwrap();
+        let b = arena.submit(mv("b", Load, None)).unwrap();
+        let _c = arena.submit(mv("c", Load, None)).unwrap();
+        assert!(!arena.state.is_over());
+        assert_eq!(vec![("b".to_string(), LoseGame)], arena.take_outcomes());
+        match block_on(b) {
+            Ok(ArenaResult { .. }) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn invalid_moves_are_refused() {
+        let mut arena = Arena::new(GameState::new(1, vec!["a".into(), "b".into(), "c".into()]));
+        assert_eq!(Some(Violation::NotInGame(1)), arena.submit(mv("d", Load, None)).err());
+        assert_eq!(Some(Violation::InvalidMove(Timeout)), arena.submit(mv("a", Timeout, None)).err());
+        assert_eq!(Some(Violation::InvalidTarget("a".into())), arena.submit(mv("a", Shoot, Some("a"))).err());
+        assert_eq!(Some(Violation::InvalidTarget("d".into())), arena.submit(mv("a", Shoot, Some("d"))).err());
+        arena.forfeit("c");
+        assert_eq!(Some(Violation::Eliminated(1)), arena.submit(mv("c", Load, None)).err());
+        assert!(arena.submit(mv("a", Load, None)).is_ok());
+    }
+
+    #[test]
+    fn abort_ends_round() {
+        let mut arena = Arena::new(GameState::new(1, vec!["a".into(), "b".into()]));
+        let a = arena.submit(mv("a", Load, None)).unwrap();
+        arena.abort(ErrorCode::ShuttingDown, "the server is shutting down");
+        assert_eq!(Ok(Error { code: ErrorCode::ShuttingDown, message: "the server is shutting down".into() }), block_on(a));
+        assert_eq!(vec![("a".to_string(), ErrorEnd), ("b".to_string(), ErrorEnd)], arena.take_outcomes());
+        assert!(arena.rounds.is_empty());
+    }
+
+    #[test]
+    fn last_round_ends_game() {
+        let mut arena = Arena::new(GameState::new(1, vec!["a".into(), "b".into()]));
+        let _a = arena.submit(mv("a", Load, None));
+        let _b = arena.submit(mv("b", Load, None));
+        let a = arena.submit(mv("a", Shoot, None)).unwrap();
+        let b = arena.submit(mv("b", Load, None)).unwrap();
+        assert_eq!(Ok(RoundResult { a: Shoot, b: Load }), block_on(a));
+        assert_eq!(Ok(RoundResult { a: Shoot, b: Load }), block_on(b));
+        assert_eq!(vec![("a".to_string(), WinGame), ("b".to_string(), LoseGame)], arena.take_outcomes());
+        assert_eq!(2, arena.rounds.len());
+        assert_eq!(vec![mv("a", Shoot, Some("b")), mv("b", Load, None)], arena.rounds[1].moves);
+        assert!(!arena.rounds[1].players[1].alive);
+    }
+}
@@ -0,0 +1,259 @@
+//! The `--config` file, in TOML
+//!
+//! Everything starts out with its default, the file overrides that and the command line overrides the file.
+//! `gameserver.example.toml` lists every setting.
+
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use shotgun_common::strategy::Strategy;
+use crate::Settings;
+
+/// Where game connections are accepted
+#[derive(Debug, Clone, PartialEq)]
+pub enum Listen {
+    /// IPv6 addresses accept no IPv4, list both for dual-stack
+    Ip(IpAddr),
+    /// `unix:<PATH>`, for local test harnesses, never encrypted
+    Unix(PathBuf),
+}
+
+impl FromStr for Listen {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Listen, String> {
+        match s.strip_prefix("unix:") {
+            Some("") => Err("unix: needs a path".into()),
+            Some(path) => Ok(Listen::Unix(path.into())),
+            None => s.parse().map(Listen::Ip).map_err(|_| format!("{:?} is neither an IP address nor unix:<PATH>", s)),
+        }
+    }
+}
+
+/// Everything the gameserver is started with
+pub struct Config {
+    pub listen: Vec<Listen>,
+    pub port: u16,
+    pub websocket_port: Option<u16>,
+    pub http_port: Option<u16>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub shutdown_grace: Duration,
+    pub data_dir: Option<PathBuf>,
+    pub log_level: String,
+    pub log_json: bool,
+    /// The admin channel listens on its own address, the loopback by default
+    pub admin_listen: String,
+    pub admin_port: Option<u16>,
+    pub admin_token: Option<String>,
+    /// Rules, matchmaking and limits
+    pub settings: Settings,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            listen: vec![Listen::Ip("::1".parse().unwrap())],
+            port: 6000,
+            websocket_port: None,
+            http_port: None,
+            tls_cert: None,
+            tls_key: None,
+            shutdown_grace: Duration::from_secs(30),
+            data_dir: None,
+            log_level: "info".into(),
+            log_json: false,
+            admin_listen: "::1".into(),
+            admin_port: None,
+            admin_token: None,
+            settings: Settings {
+                house_bot: Some(Strategy::Coward),
+                ..Settings::default()
+            },
+        }
+    }
+}
+
+impl Config {
+    /// The defaults with everything set in the file at `path` instead
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut config = Config::default();
+        config.apply(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(config)
+    }
+
+    /// Set everything given in the TOML `text`, unknown settings are an error
+    pub fn apply(&mut self, text: &str) -> Result<(), String> {
+        let table: toml::Table = text.parse().map_err(|e: toml::de::Error| e.message().to_string())?;
+        for (name, section) in &table {
+            let section = section.as_table().ok_or_else(|| format!("{}: expected a section like [{}]", name, name))?;
+            for (key, value) in section {
+                let key = format!("{}.{}", name, key);
+                let settings = &mut self.settings;
+                match &*key {
+                    "server.listen" => self.listen = listen(&key, value)?,
+                    "server.port" => self.port = port(&key, value)?,
+                    "server.websocket_port" => self.websocket_port = Some(port(&key, value)?),
+                    "server.http_port" => self.http_port = Some(port(&key, value)?),
+                    "server.tls_cert" => self.tls_cert = Some(string(&key, value)?.into()),
+                    "server.tls_key" => self.tls_key = Some(string(&key, value)?.into()),
+                    "server.shutdown_grace" => self.shutdown_grace = millis(&key, value)?,
+                    "game.max_round_length" => settings.max_round_length = millis(&key, value)?,
+                    "game.friendly_fire" => settings.friendly_fire = boolean(&key, value)?,
+                    "matchmaking.house_bot" => settings.house_bot = house_bot(&string(&key, value)?).map_err(|e| format!("{}: {}", key, e))?,
+                    "matchmaking.house_bot_wait" => settings.house_bot_wait = millis(&key, value)?,
+                    "matchmaking.resume_grace" => settings.resume_grace = millis(&key, value)?,
+                    "persistence.data_dir" => self.data_dir = Some(string(&key, value)?.into()),
+                    "persistence.registry" => settings.registry = Some(string(&key, value)?.into()),
+                    "limits.max_games" => settings.limits.games_per_nickname = count(&key, value)?,
+                    "limits.max_new_games" => settings.limits.new_games_per_second = count(&key, value)?,
+                    "limits.max_parse_errors" => settings.limits.parse_errors = count(&key, value)?,
+                    "limits.max_connections_per_ip" => settings.limits.connections_per_ip = count(&key, value)?,
+                    "logging.level" => self.log_level = string(&key, value)?,
+                    "logging.json" => self.log_json = boolean(&key, value)?,
+                    "admin.listen" => self.admin_listen = string(&key, value)?,
+                    "admin.port" => self.admin_port = Some(port(&key, value)?),
+                    "admin.token" => self.admin_token = Some(string(&key, value)?),
+                    _ => return Err(format!("{}: unknown setting", key)),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Settings that do not fit together, after the command line overrode the file
+    pub fn validate(&self) -> Result<(), String> {
+        if self.listen.is_empty() {
+            return Err("listen: needs at least one address".into())
+        }
+        self.admin_listen.parse::<IpAddr>().map_err(|_| format!("admin.listen: {:?} is no IP address", self.admin_listen))?;
+        if self.admin_port.is_some() && self.admin_token.as_deref().unwrap_or("").is_empty() {
+            return Err("admin.port needs an admin.token".into())
+        }
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            return Err("tls_cert and tls_key go together".into())
+        }
+        if self.settings.max_round_length == Duration::from_millis(0) {
+            return Err("max_round_length: must be more than 0 ms".into())
+        }
+        let ports = [Some(self.port), self.websocket_port, self.http_port, self.admin_port];
+        for (i, port) in ports.iter().enumerate() {
+            if port.is_some() && ports[i + 1..].contains(port) {
+                return Err(format!("port {} is used twice", port.unwrap()))
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The house bot strategy, or `none`
+pub fn house_bot(name: &str) -> Result<Option<Strategy>, String> {
+    match name {
+        "none" => Ok(None),
+        strategy => strategy.parse().map(Some).map_err(|_| format!("unknown strategy {:?}, try coward, gunslinger or none", strategy)),
+    }
+}
+
+fn string(key: &str, value: &toml::Value) -> Result<String, String> {
+    value.as_str().map(|s| s.to_string()).ok_or_else(|| format!("{}: expected a string", key))
+}
+
+/// One address or a list of them
+fn listen(key: &str, value: &toml::Value) -> Result<Vec<Listen>, String> {
+    let entries = match value.as_array() {
+        Some(entries) => entries.iter().map(|entry| string(key, entry)).collect::<Result<Vec<String>, String>>()?,
+        None => vec![string(key, value)?],
+    };
+    entries.iter().map(|entry| entry.parse().map_err(|e| format!("{}: {}", key, e))).collect()
+}
+
+fn boolean(key: &str, value: &toml::Value) -> Result<bool, String> {
+    value.as_bool().ok_or_else(|| format!("{}: expected true or false", key))
+}
+
+fn count(key: &str, value: &toml::Value) -> Result<usize, String> {
+    match value.as_integer() {
+        Some(n) if n >= 0 => Ok(n as usize),
+        _ => Err(format!("{}: expected a number of at least 0", key)),
+    }
+}
+
+fn millis(key: &str, value: &toml::Value) -> Result<Duration, String> {
+    count(key, value).map(|ms| Duration::from_millis(ms as u64)).map_err(|_| format!("{}: expected milliseconds", key))
+}
+
+fn port(key: &str, value: &toml::Value) -> Result<u16, String> {
+    match value.as_integer() {
+        Some(n) if n > 0 && n <= i64::from(u16::MAX) => Ok(n as u16),
+        _ => Err(format!("{}: expected a port from 1 to 65535", key)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_is_valid() {
+        let mut config = Config::default();
+        config.apply(include_str!("../gameserver.example.toml")).unwrap();
+        assert_eq!(Ok(()), config.validate());
+        assert_eq!(Some(6081), config.http_port);
+        assert_eq!(Duration::from_millis(200), config.settings.max_round_length);
+        assert_eq!(Some(Strategy::Gunslinger), config.settings.house_bot);
+        assert_eq!(3, config.settings.limits.parse_errors);
+    }
+
+    #[test]
+    fn unknown_and_mistyped_settings() {
+        let mut config = Config::default();
+        assert_eq!(Err("server.prot: unknown setting".into()), config.apply("[server]\nprot = 6000"));
+        assert_eq!(Err("limits.max_games: expected a number of at least 0".into()), config.apply("[limits]\nmax_games = -1"));
+        assert_eq!(Err("server.port: expected a port from 1 to 65535".into()), config.apply("[server]\nport = 70000"));
+        assert_eq!(Err("game: expected a section like [game]".into()), config.apply("game = 1"));
+        assert!(config.apply("[matchmaking]\nhouse_bot = \"sniper\"").unwrap_err().starts_with("matchmaking.house_bot: unknown strategy"));
+        assert!(config.apply("[server\n").is_err());
+    }
+
+    #[test]
+    fn validation() {
+        let mut config = Config::default();
+        assert_eq!(Ok(()), config.validate());
+        config.tls_cert = Some("cert.pem".into());
+        assert!(config.validate().is_err());
+        config.tls_key = Some("key.pem".into());
+        assert_eq!(Ok(()), config.validate());
+        config.http_port = Some(6000);
+        assert_eq!(Err("port 6000 is used twice".into()), config.validate());
+        config.http_port = None;
+        config.admin_port = Some(6001);
+        assert_eq!(Err("admin.port needs an admin.token".into()), config.validate());
+        config.admin_token = Some("secret".into());
+        assert_eq!(Ok(()), config.validate());
+        config.settings.max_round_length = Duration::from_millis(1500);
+        assert_eq!(Ok(()), config.validate());
+        config.settings.max_round_length = Duration::from_millis(0);
+        assert!(config.validate().is_err());
+        config.settings.max_round_length = Duration::from_millis(200);
+        config.listen = vec![];
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn listen_entries() {
+        let mut config = Config::default();
+        config.apply("[server]\nlisten = [\"0.0.0.0\", \"::\", \"unix:/tmp/shotgun.sock\"]").unwrap();
+        assert_eq!(vec![
+            Listen::Ip("0.0.0.0".parse().unwrap()),
+            Listen::Ip("::".parse().unwrap()),
+            Listen::Unix("/tmp/shotgun.sock".into()),
+        ], config.listen);
+        config.apply("[server]\nlisten = \"127.0.0.1\"").unwrap();
+        assert_eq!(vec![Listen::Ip("127.0.0.1".parse().unwrap())], config.listen);
+        assert_eq!(Err("server.listen: \"localhost\" is neither an IP address nor unix:<PATH>".into()), config.apply("[server]\nlisten = [\"localhost\"]"));
+        assert!("unix:".parse::<Listen>().is_err());
+    }
+}
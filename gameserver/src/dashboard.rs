@@ -0,0 +1,31 @@
+//! The web dashboard, compiled into the binary and served next to the HTTP API
+
+use shotgun_common::http::Response;
+
+/// Path, content type and content of every file of the dashboard
+const ASSETS: &[(&str, &str, &str)] = &[
+    ("/", "text/html; charset=utf-8", include_str!("../static/index.html")),
+    ("/dashboard.js", "application/javascript; charset=utf-8", include_str!("../static/dashboard.js")),
+    ("/dashboard.css", "text/css; charset=utf-8", include_str!("../static/dashboard.css")),
+];
+
+/// `None` if the path is not part of the dashboard
+pub fn asset(path: &str) -> Option<Response> {
+    ASSETS.iter()
+        .find(|asset| asset.0 == path)
+        .map(|&(_, content_type, content)| Response::new(200).body(content_type, content.as_bytes().to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn index_links_assets() {
+        let index = String::from_utf8(asset("/").unwrap().body).unwrap();
+        for &(path, _, _) in &ASSETS[1..] {
+            assert!(index.contains(&format!("\"{}\"", &path[1..])), "{} is not linked", path);
+        }
+        assert!(asset("/index.php").is_none());
+    }
+}
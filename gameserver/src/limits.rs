@@ -0,0 +1,87 @@
+//! Limits against misbehaving bots
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Zero means unlimited
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct Limits {
+    /// Games a nickname plays or waits for at the same time
+    pub games_per_nickname: usize,
+    /// New game requests of one connection within a second
+    pub new_games_per_second: usize,
+    /// Unparsable lines of one connection, the next one closes it
+    pub parse_errors: usize,
+    /// Open game connections from one IP address
+    pub connections_per_ip: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            games_per_nickname: 10,
+            new_games_per_second: 5,
+            parse_errors: 10,
+            connections_per_ip: 32,
+        }
+    }
+}
+
+/// Is `count` beyond `limit`, where zero means unlimited?
+pub fn exceeds(count: usize, limit: usize) -> bool {
+    limit > 0 && count > limit
+}
+
+/// Counts events within the last second
+#[derive(Default)]
+pub struct RateLimiter {
+    recent: VecDeque<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new() -> RateLimiter {
+        RateLimiter::default()
+    }
+
+    /// Records the event at `now` unless it is one too many for `per_second`, which may change in between
+    pub fn allow(&mut self, now: Instant, per_second: usize) -> bool {
+        while self.recent.front().is_some_and(|&t| now.duration_since(t) >= Duration::from_secs(1)) {
+            self.recent.pop_front();
+        }
+        if exceeds(self.recent.len() + 1, per_second) {
+            return false
+        }
+        self.recent.push_back(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limiter_allows_per_second() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new();
+        assert!(limiter.allow(start, 2));
+        assert!(limiter.allow(start + Duration::from_millis(100), 2));
+        assert!(!limiter.allow(start + Duration::from_millis(200), 2));
+        assert!(limiter.allow(start + Duration::from_millis(1000), 2));
+        assert!(!limiter.allow(start + Duration::from_millis(1050), 2));
+        assert!(limiter.allow(start + Duration::from_millis(1100), 2));
+        // A reloaded limit applies right away
+        assert!(!limiter.allow(start + Duration::from_millis(1150), 2));
+        assert!(limiter.allow(start + Duration::from_millis(1150), 3));
+    }
+
+    #[test]
+    fn zero_is_unlimited() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new();
+        assert!((0..100).all(|_| limiter.allow(start, 0)));
+        assert!(!exceeds(1000, 0));
+        assert!(exceeds(3, 2));
+        assert!(!exceeds(2, 2));
+    }
+}
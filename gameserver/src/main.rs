@@ -2,134 +2,2038 @@
 extern crate rustc_serialize;
 extern crate docopt;
 extern crate futures;
-extern crate tokio_proto;
-extern crate tokio_service;
+extern crate tokio;
 extern crate shotgun_common;
+#[macro_use] extern crate tracing;
+extern crate signal_hook;
+extern crate socket2;
+
+mod admin;
+mod api;
+mod arena;
+mod config;
+mod dashboard;
+mod limits;
+mod metrics;
+mod series;
+mod shards;
+mod violation;
 
 //use std::io::prelude::*;
 //use std::net::TcpStream;
 
+use std::fs;
+use std::mem;
 use std::io;
-use std::net::SocketAddr;
-use std::sync::Arc;
-use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Instant;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
 
-use tokio_proto::TcpServer;
+use rustc_serialize::json::Json;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use futures::{future, stream, FutureExt, Sink, SinkExt, Stream, StreamExt, TryFutureExt};
+use futures::future::BoxFuture;
+use futures::channel::{mpsc, oneshot};
+use tokio::io::{AsyncRead, AsyncWrite};
+use socket2::{Domain, Socket, Type};
+use tokio::net::{TcpListener, UnixListener};
+use tracing::{field, Span};
 use shotgun_common::*;
 use shotgun_common::ParsedLine::*;
 use shotgun_common::networking::*;
+use shotgun_common::strategy::Strategy;
+use shotgun_common::auth;
+use shotgun_common::auth::Registry;
+use shotgun_common::logging;
+use shotgun_common::http;
+use shotgun_common::tls;
+use shotgun_common::websocket;
+use crate::admin::Admin;
+use crate::arena::Arena;
+use crate::config::{Config, Listen};
+use crate::limits::{Limits, RateLimiter};
+use crate::metrics::Metrics;
+use crate::series::MatchSeries;
+use crate::shards::Shards;
+use crate::violation::Violation;
 
+/// Flags that are not given stay `None`, so the `--config` file or the default applies
 #[derive(Debug,RustcDecodable)]
 struct Args {
-    flag_port  : u16,
-    flag_listen: String,
+    flag_config: Option<String>,
+    flag_port  : Option<u16>,
+    flag_listen: Vec<String>,
+    flag_friendly_fire: bool,
+    flag_house_bot: Option<String>,
+    flag_house_bot_wait: Option<u64>,
+    flag_resume_grace: Option<u64>,
+    flag_registry: Option<String>,
+    flag_tls_cert: Option<String>,
+    flag_tls_key: Option<String>,
+    flag_websocket_port: Option<u16>,
+    flag_http_port: Option<u16>,
+    flag_log_level: Option<String>,
+    flag_log_json: bool,
+    flag_shutdown_grace: Option<u64>,
+    flag_data_dir: Option<String>,
+    flag_max_games: Option<usize>,
+    flag_max_new_games: Option<usize>,
+    flag_max_parse_errors: Option<usize>,
+    flag_max_connections_per_ip: Option<usize>,
+    flag_admin_port: Option<u16>,
+    flag_admin_token: Option<String>,
 }
 
-static USAGE: &'static str = "
+static USAGE: &str = "
 Shotgun Gameserver
 
 Usage:
-  shotgun_gameserver [--listen=<IP>] [--port=<PORT>]
+  shotgun_gameserver [--config=<FILE>] [--listen=<ADDR>...] [--port=<PORT>] [--friendly-fire] [--house-bot=<STRATEGY>] [--house-bot-wait=<MS>] [--resume-grace=<MS>] [--registry=<FILE>] [--tls-cert=<FILE> --tls-key=<FILE>] [--websocket-port=<PORT>] [--http-port=<PORT>] [--log-level=<LEVEL>] [--log-json] [--shutdown-grace=<MS>] [--data-dir=<DIR>]
+                     [--max-games=<N>] [--max-new-games=<N>] [--max-parse-errors=<N>] [--max-connections-per-ip=<N>]
+                     [--admin-port=<PORT> --admin-token=<TOKEN>]
   shotgun_gameserver (-h | --help)
 
 Options:
-    --port=<PORT>    The port to listen on [default: 6000]
-    --listen=<IP>    The socket address to listen on [default: ::1]
+    --config=<FILE>            Read the settings from this TOML file, the flags override it
+    --port=<PORT>              The port to listen on, 6000 by default
+    --listen=<ADDR>            An IP address or unix:<PATH> to accept game connections on, repeat it for more, ::1 by default
+    --friendly-fire            Teammates can hit each other in team games
+    --house-bot=<STRATEGY>     Opponent filling in if nobody else wants to play: coward (the default), gunslinger or none
+    --house-bot-wait=<MS>      How long a player waits for an opponent before the house bot steps in, 5000 by default
+    --resume-grace=<MS>        How long the games of a dropped connection wait for a `Resume`, its rounds count as Timeout meanwhile, 30000 by default
+    --registry=<FILE>          Nicknames registered with `shotgun_admin register` need their token
+    --tls-cert=<FILE>          Accept only TLS connections with this PEM certificate chain
+    --tls-key=<FILE>           The PEM private key of the --tls-cert
+    --websocket-port=<PORT>    Also accept WebSocket connections on this port, one line per text frame
+    --http-port=<PORT>         Serve the dashboard and statistics, live games and replays as JSON on this port
+    --log-level=<LEVEL>        error, warn, info (the default), debug or trace, optionally per module like `shotgun_gameserver=debug,info`
+    --log-json                 Log one JSON object per line instead of plain text
+    --shutdown-grace=<MS>      On SIGTERM or SIGINT running games may finish for so long, the rest ends with ErrorEnd, 30000 by default
+    --data-dir=<DIR>           Write statistics.json and replays.json here before shutting down
+    --max-games=<N>            Games a nickname plays or waits for at the same time, 0 for unlimited, 10 by default
+    --max-new-games=<N>        New game requests of a connection per second, 0 for unlimited, 5 by default
+    --max-parse-errors=<N>     Unparsable lines a connection may send, the next one closes it, 0 for unlimited, 10 by default
+    --max-connections-per-ip=<N>  Open game connections from one IP address, 0 for unlimited, 32 by default
+    --admin-port=<PORT>        Accept admin commands on this port, on the loopback address unless the --config file says otherwise
+    --admin-token=<TOKEN>      The admin commands must authenticate with it, better set it in the --config file
 ";
 
-fn main() {
+impl Args {
+    /// The `--config` file or the defaults, with whatever is given on the command line instead
+    ///
+    /// Read on startup and again on SIGHUP.
+    fn configure(&self) -> Result<Config, String> {
+        let mut config = match self.flag_config {
+            Some(ref path) => Config::load(Path::new(path))?,
+            None => Config::default(),
+        };
+        self.override_config(&mut config)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn override_config(&self, config: &mut Config) -> Result<(), String> {
+        let settings = &mut config.settings;
+        if !self.flag_listen.is_empty() {
+            config.listen = self.flag_listen.iter().map(|l| l.parse()).collect::<Result<_, String>>().map_err(|e| format!("--listen: {}", e))?;
+        }
+        if let Some(port) = self.flag_port { config.port = port }
+        if self.flag_friendly_fire { settings.friendly_fire = true }
+        if let Some(ref name) = self.flag_house_bot { settings.house_bot = config::house_bot(name).map_err(|e| format!("--house-bot: {}", e))? }
+        if let Some(ms) = self.flag_house_bot_wait { settings.house_bot_wait = Duration::from_millis(ms) }
+        if let Some(ms) = self.flag_resume_grace { settings.resume_grace = Duration::from_millis(ms) }
+        if let Some(ref path) = self.flag_registry { settings.registry = Some(path.into()) }
+        if let Some(ref path) = self.flag_tls_cert { config.tls_cert = Some(path.into()) }
+        if let Some(ref path) = self.flag_tls_key { config.tls_key = Some(path.into()) }
+        if let Some(port) = self.flag_websocket_port { config.websocket_port = Some(port) }
+        if let Some(port) = self.flag_http_port { config.http_port = Some(port) }
+        if let Some(ref level) = self.flag_log_level { config.log_level = level.clone() }
+        if self.flag_log_json { config.log_json = true }
+        if let Some(ms) = self.flag_shutdown_grace { config.shutdown_grace = Duration::from_millis(ms) }
+        if let Some(ref path) = self.flag_data_dir { config.data_dir = Some(path.into()) }
+        if let Some(n) = self.flag_max_games { settings.limits.games_per_nickname = n }
+        if let Some(n) = self.flag_max_new_games { settings.limits.new_games_per_second = n }
+        if let Some(n) = self.flag_max_parse_errors { settings.limits.parse_errors = n }
+        if let Some(n) = self.flag_max_connections_per_ip { settings.limits.connections_per_ip = n }
+        if let Some(port) = self.flag_admin_port { config.admin_port = Some(port) }
+        if let Some(ref token) = self.flag_admin_token { config.admin_token = Some(token.clone()) }
+        Ok(())
+    }
+}
+
+#[tokio::main]
+async fn main() {
     // allways print backtrace
     std::env::set_var("RUST_BACKTRACE", "1");
 
-    let args: Args = docopt::Docopt::new(USAGE).and_then(|d| d.decode())
-                                       .unwrap_or_else(|e| e.exit());
+    let args: Arc<Args> = Arc::new(docopt::Docopt::new(USAGE).and_then(|d| d.decode())
+                                       .unwrap_or_else(|e| e.exit()));
+
+    let mut config = args.configure().unwrap_or_else(|e| {
+        eprintln!("invalid configuration: {}", e);
+        std::process::exit(2)
+    });
+
+    logging::init(&config.log_level, config.log_json).unwrap_or_else(|e| panic!("--log-level: {}", e));
+
+    info!(listen = ?config.listen, port = config.port, "starting shotgun_gameserver");
+
+    let arena_server = ArenaServer::new(mem::take(&mut config.settings));
+
+    let janitor = arena_server.clone();
+    thread::spawn(move || {
+        let mut last = Instant::now();
+        loop {
+            janitor.time_out_rounds(HOUSE_KEEPING_INTERVAL);
+            if last.elapsed() >= HOUSE_KEEPING_INTERVAL {
+                janitor.house_keeping();
+                last = Instant::now();
+            }
+        }
+    });
+
+    let mut signals = Signals::new([SIGTERM, SIGINT, SIGHUP]).expect("can not handle signals");
+    let shutdown = arena_server.clone();
+    let grace = config.shutdown_grace;
+    let data_dir = config.data_dir;
+    let reload = args.clone();
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if signal == SIGHUP {
+                // Listeners, TLS and logging stay as they are until a restart
+                match reload.configure() {
+                    Ok(config) => shutdown.reload(config.settings),
+                    Err(e) => error!(error = %e, "invalid configuration, keeping the current one"),
+                }
+                continue
+            }
+            info!(signal, "shutting down");
+            shutdown.shutdown(grace);
+            if let Some(dir) = data_dir {
+                match shutdown.save(&dir) {
+                    Ok(()) => info!(?dir, "saved statistics and replays"),
+                    Err(e) => error!(?dir, error = %e, "can not save statistics and replays"),
+                }
+            }
+            // Give the connection tasks a moment to write the last answers
+            thread::sleep(Duration::from_millis(200));
+            std::process::exit(0);
+        }
+    });
+
+    let tls = match (config.tls_cert, config.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::server_config(&cert, &key)
+            .unwrap_or_else(|e| panic!("can not load TLS certificate: {}", e))),
+        _ => None,
+    };
+
+    // Every address gets the game, WebSocket and HTTP ports, all served by the same `ArenaServer`
+    for entry in &config.listen {
+        let ip = match *entry {
+            Listen::Ip(ip) => ip,
+            Listen::Unix(ref path) => {
+                info!(?path, "accepting game connections");
+                tokio::spawn(listen(bind_unix(path), Protocol::Lines, None, arena_server.clone()));
+                continue
+            }
+        };
+        let addr = SocketAddr::new(ip, config.port);
+        info!(%addr, "accepting game connections");
+        tokio::spawn(listen(bind(addr), Protocol::Lines, tls.clone(), arena_server.clone()));
+
+        if let Some(port) = config.websocket_port {
+            let addr = SocketAddr::new(ip, port);
+            info!(%addr, "accepting WebSocket connections");
+            tokio::spawn(listen(bind(addr), Protocol::WebSocket, tls.clone(), arena_server.clone()));
+        }
+
+        if let Some(port) = config.http_port {
+            let addr = SocketAddr::new(ip, port);
+            info!(%addr, "serving the HTTP API");
+            tokio::spawn(listen(bind(addr), Protocol::Http, None, arena_server.clone()));
+        }
+    }
+
+    if let (Some(port), Some(token)) = (config.admin_port, config.admin_token) {
+        let addr = SocketAddr::new(config.admin_listen.parse().unwrap(), port);
+        info!(%addr, "accepting admin commands");
+        let admin = Admin {
+            token,
+            configure: Box::new(move || args.configure().map(|config| config.settings)),
+        };
+        tokio::spawn(listen(bind(addr), Protocol::Admin(Arc::new(admin)), None, arena_server));
+    }
+
+    // The listeners run until a signal ends the process
+    future::pending::<()>().await
+}
+
+fn bind(addr: SocketAddr) -> Listener {
+    Listener::Tcp(tcp_listener(addr).unwrap_or_else(|e| panic!("can not listen on {}: {}", addr, e)))
+}
+
+/// IPv6 sockets accept no IPv4, so `::` and `0.0.0.0` can listen on the same port side by side
+fn tcp_listener(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// A socket file left over from an earlier run is replaced
+fn bind_unix(path: &Path) -> Listener {
+    if fs::symlink_metadata(path).map(|m| m.file_type().is_socket()).unwrap_or(false) {
+        let _ = fs::remove_file(path);
+    }
+    Listener::Unix(UnixListener::bind(path).unwrap_or_else(|e| panic!("can not listen on {}: {}", path.display(), e)))
+}
+
+/// Accepts connections over TCP or a Unix socket
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// What the clients of a listener speak
+#[derive(Clone)]
+enum Protocol {
+    Lines,
+    WebSocket,
+    Http,
+    Admin(Arc<Admin>),
+}
+
+/// Every accepted connection is served in a task of its own
+async fn listen(listener: Listener, protocol: Protocol, tls: Option<Arc<tls::ServerConfig>>, server: Arc<ArenaServer>) {
+    loop {
+        let (protocol, tls, server) = (protocol.clone(), tls.clone(), server.clone());
+        let result = match listener {
+            Listener::Tcp(ref listener) => listener.accept().await.map(|(socket, peer)| {
+                tokio::spawn(accepted(socket, Some(peer), protocol, tls, server));
+            }),
+            // Local connections are not limited per address
+            Listener::Unix(ref listener) => listener.accept().await.map(|(socket, _)| {
+                tokio::spawn(accepted(socket, None, protocol, tls, server));
+            }),
+        };
+        if let Err(e) = result {
+            warn!(error = %e, "can not accept connection");
+        }
+    }
+}
+
+/// Serve one connection, `peer` is the address of a TCP client
+async fn accepted<T>(socket: T, peer: Option<SocketAddr>, protocol: Protocol, tls: Option<Arc<tls::ServerConfig>>, server: Arc<ArenaServer>)
+    where T: AsyncRead + AsyncWrite + Unpin
+{
+    // Only game connections count towards the limit, not the HTTP or admin ones
+    let counted = peer.filter(|_| matches!(protocol, Protocol::Lines | Protocol::WebSocket));
+    let admitted = counted.is_none_or(|peer| server.open_from(peer.ip()));
+    let result = match tls {
+        Some(config) => match tls::accept(config, socket).await {
+            Ok(stream) => connection(stream, protocol, server.clone(), admitted).await,
+            Err(e) => {
+                server.metrics.handshake_failed(&e);
+                Err(e)
+            }
+        },
+        None => connection(socket, protocol, server.clone(), admitted).await,
+    };
+    if let Some(peer) = counted {
+        server.close_from(peer.ip());
+    }
+    if let Err(e) = result {
+        debug!(?peer, error = %e, "connection failed");
+    }
+}
+
+/// Game connections beyond `--max-connections-per-ip` are not `admitted`, they only get told so
+async fn connection<T>(io: T, protocol: Protocol, server: Arc<ArenaServer>, admitted: bool) -> io::Result<()>
+    where T: AsyncRead + AsyncWrite + Unpin
+{
+    match protocol {
+        Protocol::Lines => serve_player(lines(io), server, admitted).await,
+        Protocol::WebSocket => match websocket::upgrade(io).await {
+            Ok(transport) => serve_player(transport, server, admitted).await,
+            Err(e) => {
+                server.metrics.handshake_failed(&e);
+                Err(e)
+            }
+        },
+        Protocol::Http => http::serve(io, |request| api::handle(&server, &request)).await,
+        Protocol::Admin(admin) => admin::serve(io, server, admin).await,
+    }
+}
+
+/// The handshake, then every request of the player until the connection closes
+async fn serve_player<T>(mut transport: T, server: Arc<ArenaServer>, admitted: bool) -> io::Result<()>
+    where T: Stream<Item = io::Result<ParsedLine>> + Sink<ParsedLine, Error = io::Error> + Unpin
+{
+    if !admitted {
+        server.metrics.rate_limited.inc("connections_per_ip");
+        return transport.send(ParsedLine::Error { code: ErrorCode::RateLimited, message: "too many connections from this address".into() }).await
+    }
+    let (outbox, pushed) = mpsc::channel(OUTBOX_SIZE);
+    // A full outbox is closed, once the lines in it are sent the client is told why
+    let pushed = pushed.chain(stream::once(future::ready(ParsedLine::Error { code: ErrorCode::RateLimited, message: "too many lines waiting to be sent".into() })));
+    let service = ArenaService::new(server.clone(), outbox);
+    let hello = match handshake(&mut transport).await {
+        Ok(hello) => hello,
+        Err(e) => {
+            server.metrics.handshake_failed(&e);
+            return Err(e)
+        }
+    };
+    let mut parse_errors = 0;
+    serve(transport, hello, pushed, |request| service.call(request), |e| {
+        if e.get_ref().is_none_or(|inner| !inner.is::<ParseError>()) {
+            return Err(e)
+        }
+        server.metrics.count_error(&e);
+        parse_errors += 1;
+        if limits::exceeds(parse_errors, server.settings().limits.parse_errors) {
+            server.metrics.rate_limited.inc("parse_errors");
+            return Ok(ParsedLine::Error { code: ErrorCode::RateLimited, message: "too many unparsable lines".into() })
+        }
+        Ok(MultiplexedMessage { game_id: 0, action: Action::Error { code: ErrorCode::InvalidLine, message: e.to_string() } })
+    }).await
+}
+
+/// Lines pushed to one connection, whenever the server likes
+type Outbox = mpsc::Sender<ParsedLine>;
 
-    let touple = format!("[{}]:{}", args.flag_listen, args.flag_port);
-    println!("Starting shotgun_gameserver: {}", touple);
-    let addr = (&*touple).parse::<SocketAddr>().unwrap();
+/// Lines waiting to be sent to one connection, a client that falls further behind is disconnected
+const OUTBOX_SIZE: usize = 1024;
 
-    // The builder requires a protocol and an address
-    let server = TcpServer::new(LineProto::new(), addr);
+/// A player waiting for an opponent
+struct Waiting {
+    since: Instant,
+    reply: oneshot::Sender<ParsedLine>,
+}
 
-    let arena_server = ArenaServer::new();
+impl Waiting {
+    fn new(reply: oneshot::Sender<ParsedLine>) -> Waiting {
+        Waiting { since: Instant::now(), reply }
+    }
 
-    // We provide a way to *instantiate* the service for each new
-    // connection; here, we just immediately return a new instance.
-    server.serve(move || Ok(ArenaService {
-        server: arena_server.clone(),
-    }));
+    /// The connection closed while it waited
+    fn is_gone(&self) -> bool {
+        self.reply.is_canceled()
+    }
+}
+
+/// Players waiting in the lobby, by arena size or match length
+type Lobby = HashMap<u64, Vec<(String, Waiting)>>;
+/// Players waiting for a team game, by team id
+type TeamLobby = Vec<(String, u64, Waiting)>;
+
+/// How many players make a team
+const TEAM_SIZE: usize = 2;
+
+/// Running games are locked in so many groups, moves in different groups never wait for each other
+const ARENA_SHARDS: usize = 64;
+
+/// How many finished games are kept for replays
+const KEPT_GAMES: usize = 1000;
+
+/// How often sessions expire and the house bot looks for lonely players
+const HOUSE_KEEPING_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Nicknames of house bots start with this, so statistics can tell them apart
+const HOUSE_BOT_PREFIX: &str = "house:";
+
+/// A challenge waiting for the answer of the challenged player
+struct PendingChallenge {
+    challenger: String,
+    challenged: String,
+    /// Tells the challenger about the answer
+    reply: oneshot::Sender<ParsedLine>,
+}
+
+/// Handed out with the `ServerHello` so a dropped connection can be resumed
+struct Session {
+    nickname: String,
+    /// Open connections using this session
+    connections: usize,
+    /// When the last one closed
+    since: Instant,
+}
+
+#[derive(Debug,Clone)]
+pub struct Settings {
+    pub max_round_length: Duration,
+    /// May teammates hit each other?
+    pub friendly_fire: bool,
+    /// Steps in for a single player after `house_bot_wait`
+    pub house_bot: Option<Strategy>,
+    pub house_bot_wait: Duration,
+    /// How long a dropped connection may be resumed before its player forfeits
+    pub resume_grace: Duration,
+    /// File with the registered nicknames, read on every handshake
+    pub registry: Option<PathBuf>,
+    pub limits: Limits,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            max_round_length: Duration::from_millis(200),
+            friendly_fire: false,
+            house_bot: None,
+            house_bot_wait: Duration::from_secs(5),
+            resume_grace: Duration::from_secs(30),
+            registry: None,
+            limits: Limits::default(),
+        }
+    }
 }
 
 pub struct ArenaServer {
+    /// Replaced on reload
+    settings: Mutex<Settings>,
     /// wins and losts for everyone
-    player_statistics: HashMap<String, (u64, u64)>,
-    /// List of active games/arenas
-    arenas: Vec<GameState>,
+    player_statistics: Mutex<HashMap<String, (u64, u64)>>,
+    /// wins and losts for every team id
+    team_statistics: Mutex<HashMap<u64, (u64, u64)>>,
+    /// Running games by game id
+    arenas: Shards<Arena>,
+    /// When the rounds time out as (deadline, game id, rounds played before), the earliest first
+    deadlines: Mutex<BinaryHeap<Reverse<(Instant, u64, usize)>>>,
+    /// Wakes the janitor for a new earliest deadline
+    deadline_added: Condvar,
+    /// The last `KEPT_GAMES` finished games, oldest first
+    finished: Mutex<VecDeque<Arena>>,
+    lobby: Mutex<Lobby>,
+    team_lobby: Mutex<TeamLobby>,
+    match_lobby: Mutex<Lobby>,
+    /// Running match series by match id
+    matches: Mutex<HashMap<u64, MatchSeries>>,
+    /// Messages handed out on the next `RequestNewGame` of a player
+    pending: Mutex<HashMap<String, VecDeque<ParsedLine>>>,
+    /// Open challenges by game id
+    challenges: Mutex<HashMap<u64, PendingChallenge>>,
+    /// Id and outbox of the open connections by nickname
+    connections: Mutex<HashMap<String, Vec<(usize, Outbox)>>>,
+    /// Sessions by token
+    sessions: Mutex<HashMap<String, Session>>,
+    next_game_id: AtomicUsize,
+    next_connection_id: AtomicUsize,
+    /// Set on shutdown, no new games are started
+    draining: AtomicBool,
+    /// Open game connections by IP address
+    connections_by_ip: Mutex<HashMap<IpAddr, usize>>,
+    metrics: Arc<Metrics>,
 }
 
 pub struct ArenaService {
     server: Arc<ArenaServer>,
+    id: usize,
+    outbox: Outbox,
+    /// Set by the `ClientHello`
+    nickname: Mutex<Option<String>>,
+    /// Token of the session, set by the `ClientHello` or `Resume`
+    session: Mutex<Option<String>>,
+    /// New game requests of this connection
+    new_games: Mutex<RateLimiter>,
+    /// Everything logged for this connection, with its id and nickname
+    span: Span,
 }
 
 impl ArenaServer {
-    fn new() -> Arc<ArenaServer> {
+    fn new(settings: Settings) -> Arc<ArenaServer> {
         Arc::new(ArenaServer {
-            player_statistics: HashMap::new(),
-            arenas: Vec::new(),
+            settings: Mutex::new(settings),
+            player_statistics: Mutex::new(HashMap::new()),
+            team_statistics: Mutex::new(HashMap::new()),
+            arenas: Shards::new(ARENA_SHARDS),
+            deadlines: Mutex::new(BinaryHeap::new()),
+            deadline_added: Condvar::new(),
+            finished: Mutex::new(VecDeque::new()),
+            lobby: Mutex::new(HashMap::new()),
+            team_lobby: Mutex::new(Vec::new()),
+            match_lobby: Mutex::new(HashMap::new()),
+            matches: Mutex::new(HashMap::new()),
+            pending: Mutex::new(HashMap::new()),
+            challenges: Mutex::new(HashMap::new()),
+            connections: Mutex::new(HashMap::new()),
+            sessions: Mutex::new(HashMap::new()),
+            next_game_id: AtomicUsize::new(1),
+            next_connection_id: AtomicUsize::new(1),
+            draining: AtomicBool::new(false),
+            connections_by_ip: Mutex::new(HashMap::new()),
+            metrics: Arc::new(Metrics::new()),
         })
     }
 
-    /*fn find_or_create_arena(player: PlayerState) -> Box<Future<Item = LineProto, Error = io::Error>> {
-        future::ok(MultiplexedMessage {
-            game_id: 0,
-            action: Action::NewGame { opponent: "me".into() },
-        }).boxed()
-    }*/
+    fn settings(&self) -> Settings {
+        self.settings.lock().unwrap().clone()
+    }
+
+    /// Apply new settings, a new round length is announced to every connection with a `ServerHello`
+    fn reload(&self, settings: Settings) {
+        let max_round_length = settings.max_round_length;
+        let previous = mem::replace(&mut *self.settings.lock().unwrap(), settings);
+        info!(?max_round_length, "settings reloaded");
+        if previous.max_round_length != max_round_length {
+            self.broadcast(ServerHello { max_round_length, session: None });
+        }
+    }
+
+    /// Registered nicknames need the right token
+    fn authenticate(&self, nickname: &str, token: Option<&str>) -> Result<(), String> {
+        let settings = self.settings();
+        let path = match settings.registry {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        let registry = Registry::load(path).map_err(|e| {
+            error!(?path, error = %e, "can not read registry");
+            "registry unavailable".to_string()
+        })?;
+        registry.check(nickname, token).map_err(|e| e.to_string())
+    }
+
+    /// The first connection of a player brings them back into their running games
+    fn connect(&self, nickname: &str, connection: usize, outbox: Outbox) {
+        let first = {
+            let mut connections = self.connections.lock().unwrap();
+            let open = connections.entry(nickname.to_string()).or_default();
+            open.push((connection, outbox));
+            open.len() == 1
+        };
+        if first {
+            self.with_games_of(nickname, |arena| arena.set_absent(nickname, false));
+        }
+    }
+
+    /// Declines all open challenges once the last connection of a player is gone
+    fn disconnect(&self, nickname: &str, connection: usize) {
+        {
+            let mut connections = self.connections.lock().unwrap();
+            if let Some(open) = connections.get_mut(nickname) {
+                open.retain(|c| c.0 != connection);
+                if !open.is_empty() {
+                    return
+                }
+            }
+            connections.remove(nickname);
+        }
+        // Until the session expires the rounds go on without the player
+        self.with_games_of(nickname, |arena| arena.set_absent(nickname, true));
+        self.start_match_games_of(nickname);
+
+        let mut challenges = self.challenges.lock().unwrap();
+        let ids: Vec<u64> = challenges.iter().filter(|c| c.1.challenged == nickname).map(|c| *c.0).collect();
+        for game_id in ids {
+            let challenge = challenges.remove(&game_id).unwrap();
+            let _ = challenge.reply.send(MultiplexedMessage { game_id, action: Action::ChallengeDeclined });
+        }
+    }
+
+    /// Push the line to every open connection
+    fn broadcast(&self, line: ParsedLine) {
+        debug!(?line, "broadcast");
+        for open in self.connections.lock().unwrap().values_mut() {
+            for (_, outbox) in open {
+                self.send_to(outbox, line.clone());
+            }
+        }
+    }
+
+    /// Send a line to every connection of the player, false if there is none
+    fn push(&self, nickname: &str, line: ParsedLine) -> bool {
+        let mut connections = self.connections.lock().unwrap();
+        let open = match connections.get_mut(nickname) {
+            Some(open) => open,
+            None => return false,
+        };
+        debug!(%nickname, ?line, "push");
+        for (_, outbox) in open {
+            self.send_to(outbox, line.clone());
+        }
+        true
+    }
+
+    /// Queue the line for one connection, a full outbox closes the connection
+    fn send_to(&self, outbox: &mut Outbox, line: ParsedLine) {
+        // The connection may be closing right now
+        if let Err(e) = outbox.try_send(line) {
+            if e.is_full() {
+                warn!("outbox full, closing the connection");
+                self.metrics.rate_limited.inc("outbox");
+                outbox.close_channel();
+            }
+        }
+    }
+
+    /// Tell the players the game is over for how it ended
+    fn push_outcomes(&self, arena: &mut Arena) {
+        let game_id = arena.state.game_id;
+        for (nickname, action) in arena.take_outcomes() {
+            self.push(&nickname, MultiplexedMessage { game_id, action });
+        }
+    }
+
+    fn is_connected(&self, nickname: &str) -> bool {
+        self.connections.lock().unwrap().contains_key(nickname)
+    }
+
+    /// Dropped the connection and may still resume
+    fn is_away(&self, nickname: &str) -> bool {
+        !self.is_connected(nickname) && self.sessions.lock().unwrap().values().any(|s| s.nickname == nickname)
+    }
+
+    fn open_session(&self, nickname: &str) -> String {
+        let token = auth::session_token();
+        self.sessions.lock().unwrap().insert(token.clone(), Session {
+            nickname: nickname.to_string(),
+            connections: 1,
+            since: Instant::now(),
+        });
+        token
+    }
+
+    /// Returns the nickname of the session, `None` if it is unknown or expired
+    fn resume(&self, token: &str) -> Option<String> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.get_mut(token)?;
+        session.connections += 1;
+        Some(session.nickname.clone())
+    }
+
+    fn close_session(&self, token: &str) {
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(token) {
+            session.connections -= 1;
+            session.since = Instant::now();
+        }
+    }
+
+    /// Players who did not resume within the grace period forfeit their running games
+    fn expire_sessions(&self) {
+        let expired: Vec<String> = {
+            let mut sessions = self.sessions.lock().unwrap();
+            let tokens: Vec<String> = sessions.iter()
+                .filter(|s| s.1.connections == 0 && s.1.since.elapsed() >= self.settings().resume_grace)
+                .map(|s| s.0.clone())
+                .collect();
+            let expired: Vec<String> = tokens.iter().filter_map(|t| sessions.remove(t)).map(|s| s.nickname).collect();
+            expired.into_iter().filter(|n| !sessions.values().any(|s| s.nickname == *n)).collect()
+        };
+        for nickname in expired {
+            if !self.is_connected(&nickname) {
+                info!(%nickname, "session expired");
+                self.with_games_of(&nickname, |arena| arena.forfeit(&nickname));
+            }
+        }
+    }
+
+    /// Change every running game of the player, the games that are over by then are finished
+    fn with_games_of<F: Fn(&mut Arena)>(&self, nickname: &str, f: F) {
+        let mut finished: Vec<Arena> = Vec::new();
+        for mut arenas in self.arenas.each() {
+            let ids: Vec<u64> = arenas.values().filter(|a| a.state.player(nickname).is_some()).map(|a| a.state.game_id).collect();
+            for game_id in ids {
+                let arena = arenas.get_mut(&game_id).unwrap();
+                let rounds = arena.rounds.len();
+                f(arena);
+                self.rounds_resolved(arena, rounds);
+                self.push_outcomes(arena);
+                if arena.state.is_over() {
+                    finished.push(arenas.remove(&game_id).unwrap());
+                }
+            }
+        }
+        for arena in finished {
+            self.finish_arena(arena);
+        }
+    }
+
+    /// Log and count the rounds resolved since `rounds`, the next round gets its deadline
+    fn rounds_resolved(&self, arena: &Arena, rounds: usize) {
+        for (i, round) in arena.rounds.iter().enumerate().skip(rounds) {
+            debug!(game_id = arena.state.game_id, round = i + 1, moves = ?round.moves, "round resolved");
+            for mv in round.moves.iter().filter(|m| m.action == RoundAction::Timeout) {
+                self.metrics.timeouts.inc(&mv.player);
+            }
+        }
+        if arena.rounds.len() > rounds && !arena.state.is_over() {
+            self.schedule_round(arena);
+        }
+    }
+
+    /// The current round of the game times out after the max round length
+    fn schedule_round(&self, arena: &Arena) {
+        let deadline = Reverse((Instant::now() + self.settings().max_round_length, arena.state.game_id, arena.rounds.len()));
+        let mut deadlines = self.deadlines.lock().unwrap();
+        deadlines.push(deadline);
+        if deadlines.peek() == Some(&deadline) {
+            self.deadline_added.notify_one();
+        }
+    }
+
+    /// Wait at most `max_wait` for the next deadline, then time out every round that is due
+    fn time_out_rounds(&self, max_wait: Duration) {
+        let mut deadlines = self.deadlines.lock().unwrap();
+        let wait = deadlines.peek().map_or(max_wait, |next| next.0 .0.saturating_duration_since(Instant::now()).min(max_wait));
+        if wait > Duration::from_millis(0) {
+            deadlines = self.deadline_added.wait_timeout(deadlines, wait).unwrap().0;
+        }
+        let now = Instant::now();
+        let mut due = Vec::new();
+        while deadlines.peek().is_some_and(|next| next.0 .0 <= now) {
+            due.push(deadlines.pop().unwrap().0);
+        }
+        drop(deadlines);
+        for (_, game_id, rounds) in due {
+            self.time_out(game_id, rounds);
+        }
+    }
+
+    /// End the round of the game unless it is already over
+    ///
+    /// A round nobody moved in ends the game without counting it. While everyone left is absent,
+    /// the game waits for them to resume or for their sessions to expire.
+    fn time_out(&self, game_id: u64, rounds: usize) {
+        let mut arenas = self.arenas.lock(game_id);
+        let arena = match arenas.get_mut(&game_id) {
+            Some(arena) if arena.rounds.len() == rounds => arena,
+            _ => return,
+        };
+        if arena.is_silent() {
+            let mut arena = arenas.remove(&game_id).unwrap();
+            drop(arenas);
+            info!(game_id, "nobody moved, game abandoned");
+            arena.abandon();
+            self.drop_game(arena, "nobody moved within the max round length");
+            return
+        }
+        arena.time_out();
+        self.rounds_resolved(arena, rounds);
+        self.push_outcomes(arena);
+        if arena.rounds.len() == rounds {
+            self.schedule_round(arena);
+        } else if arena.state.is_over() {
+            let arena = arenas.remove(&game_id).unwrap();
+            drop(arenas);
+            self.finish_arena(arena);
+        }
+    }
+
+    /// Resolves with `NewGame` or `ChallengeDeclined` as soon as the challenged player answers
+    fn challenge(&self, challenger: String, challenged: String) -> oneshot::Receiver<ParsedLine> {
+        let (tx, rx) = oneshot::channel();
+        let game_id = self.next_game_id();
+        if challenger == challenged || !self.is_connected(&challenged) {
+            let _ = tx.send(MultiplexedMessage { game_id, action: Action::ChallengeDeclined });
+            return rx
+        }
+
+        let line = MultiplexedMessage { game_id, action: Action::ChallengedBy { nickname: challenger.clone() } };
+        self.challenges.lock().unwrap().insert(game_id, PendingChallenge {
+            challenger,
+            challenged: challenged.clone(),
+            reply: tx,
+        });
+
+        // The last connection may have closed in the meantime
+        if !self.push(&challenged, line.clone()) {
+            self.push_pending(&challenged, line);
+        }
+        rx
+    }
+
+    /// Refused unless there is such a challenge for this player
+    fn answer_challenge(&self, nickname: &str, game_id: u64, accept: bool) -> Result<ParsedLine, Violation> {
+        let challenge = {
+            let mut challenges = self.challenges.lock().unwrap();
+            match challenges.get(&game_id) {
+                Some(c) if c.challenged == nickname => {},
+                _ => return Err(Violation::UnknownChallenge(game_id)),
+            }
+            challenges.remove(&game_id).unwrap()
+        };
+
+        // The challenger may have left in the meantime
+        let line = if accept && !challenge.reply.is_canceled() {
+            let state = GameState::new(game_id, vec![challenge.challenger, challenge.challenged]);
+            self.open_arena(Arena::new(state))
+        } else {
+            MultiplexedMessage { game_id, action: Action::ChallengeDeclined }
+        };
+        let _ = challenge.reply.send(line.clone());
+        Ok(line)
+    }
+
+    /// Put a new arena into play, returns the message to tell everyone in it
+    fn open_arena(&self, mut arena: Arena) -> ParsedLine {
+        // Like the next game of a match series, while a player is away
+        let absent: Vec<String> = arena.state.players.iter()
+            .map(|p| p.nickname.clone())
+            .filter(|n| self.is_away(n))
+            .collect();
+        for nickname in absent {
+            arena.set_absent(&nickname, true);
+        }
+        let game_id = arena.state.game_id;
+        let announcement = MultiplexedMessage { game_id, action: arena.announcement() };
+        let players: Vec<&str> = arena.state.players.iter().map(|p| p.nickname.as_str()).collect();
+        info!(game_id, ?players, match_id = ?arena.match_id, "game started");
+        self.metrics.games_started.inc();
+        self.schedule_round(&arena);
+        self.arenas.lock(game_id).insert(game_id, arena);
+        announcement
+    }
+
+    fn announce(&self, state: GameState, players: Vec<Waiting>) {
+        let announcement = self.open_arena(Arena::new(state));
+        for waiting in players {
+            self.metrics.queue_wait.observe(waiting.since.elapsed());
+            let _ = waiting.reply.send(announcement.clone());
+        }
+    }
+
+    fn push_pending(&self, nickname: &str, line: ParsedLine) {
+        self.pending.lock().unwrap().entry(nickname.to_string()).or_default().push_back(line);
+    }
+
+    fn take_pending(&self, nickname: &str) -> Option<ParsedLine> {
+        self.pending.lock().unwrap().get_mut(nickname).and_then(|queue| queue.pop_front())
+    }
+
+    fn next_game_id(&self) -> u64 {
+        self.next_game_id.fetch_add(1, Ordering::SeqCst) as u64
+    }
+
+    /// Wait in the lobby until `size` different players want to play
+    fn find_or_create_arena(&self, nickname: String, size: u64) -> oneshot::Receiver<ParsedLine> {
+        let (tx, rx) = oneshot::channel();
+        let mut lobby = self.lobby.lock().unwrap();
+        let queue = lobby.entry(size).or_default();
+        queue.retain(|w| !w.1.is_gone());
+        queue.push((nickname, Waiting::new(tx)));
+
+        if let Some(players) = pick_players(queue, size as usize) {
+            let state = GameState::new(self.next_game_id(), players.iter().map(|p| p.0.clone()).collect());
+            self.announce(state, players.into_iter().map(|p| p.1).collect());
+        }
+        rx
+    }
+
+    /// Let the house bot play against everyone waiting too long for a two player game
+    fn house_keeping(&self) {
+        self.expire_sessions();
+        let settings = self.settings();
+        let strategy = match settings.house_bot {
+            Some(strategy) => strategy,
+            None => return,
+        };
+        let mut expired = Vec::new();
+        {
+            let mut lobby = self.lobby.lock().unwrap();
+            if let Some(queue) = lobby.get_mut(&2) {
+                queue.retain(|w| !w.1.is_gone());
+                let mut i = 0;
+                while i < queue.len() {
+                    if queue[i].1.since.elapsed() >= settings.house_bot_wait {
+                        expired.push(queue.remove(i));
+                    } else {
+                        i += 1;
+                    }
+                }
+            }
+        }
+
+        for (nickname, waiting) in expired {
+            let house_bot = format!("{}{}", HOUSE_BOT_PREFIX, strategy.name());
+            info!(%nickname, %house_bot, "house bot steps in");
+            let mut arena = Arena::new(GameState::new(self.next_game_id(), vec![nickname, house_bot.clone()]));
+            arena.house_bot = Some((house_bot, strategy));
+            self.metrics.queue_wait.observe(waiting.since.elapsed());
+            let _ = waiting.reply.send(self.open_arena(arena));
+        }
+    }
+
+    /// Wait in the lobby for an opponent who wants to play as many games
+    fn find_or_create_match(&self, nickname: String, games: u64) -> oneshot::Receiver<ParsedLine> {
+        let (tx, rx) = oneshot::channel();
+        let mut lobby = self.match_lobby.lock().unwrap();
+        let queue = lobby.entry(games).or_default();
+        queue.retain(|w| !w.1.is_gone());
+        queue.push((nickname, Waiting::new(tx)));
+
+        if let Some(mut players) = pick_players(queue, 2) {
+            let (b, waiting_b) = players.pop().unwrap();
+            let (a, waiting_a) = players.pop().unwrap();
+            let series = MatchSeries::new(self.next_game_id(), games, [a, b]);
+            let match_id = series.match_id;
+            info!(match_id, games, players = ?series.players, "match started");
+            self.matches.lock().unwrap().insert(match_id, series);
+
+            for waiting in [waiting_a, waiting_b] {
+                self.metrics.queue_wait.observe(waiting.since.elapsed());
+                let _ = waiting.reply.send(MultiplexedMessage { game_id: match_id, action: Action::NewMatch { games } });
+            }
+        }
+        rx
+    }
+
+    /// Wait for the next game of the oldest match series of the player, None if it plays in none
+    ///
+    /// Going by the match id, everybody plays one series after the other.
+    fn next_match_game(&self, nickname: &str) -> Option<oneshot::Receiver<ParsedLine>> {
+        let mut matches = self.matches.lock().unwrap();
+        let series = matches.values_mut()
+            .filter(|s| s.players.iter().any(|p| p == nickname))
+            .min_by_key(|s| s.match_id)?;
+        let (tx, rx) = oneshot::channel();
+        series.waiting.push((nickname.to_string(), Waiting::new(tx)));
+        self.start_match_game(series);
+        Some(rx)
+    }
+
+    /// Start the next game of the series once the last one is over and both players asked for it
+    ///
+    /// A player who is not connected is not waited for, the rounds go on without it.
+    fn start_match_game(&self, series: &mut MatchSeries) {
+        series.waiting.retain(|w| !w.1.is_gone());
+        let ready = series.players.iter().all(|p| !self.is_connected(p) || series.waiting.iter().any(|w| w.0 == *p));
+        if series.running || !ready {
+            return
+        }
+        series.running = true;
+        let mut arena = Arena::new(GameState::new(self.next_game_id(), series.next_lineup()));
+        arena.match_id = Some(series.match_id);
+        let announcement = self.open_arena(arena);
+        for player in series.players.clone() {
+            if let Some(i) = series.waiting.iter().position(|w| w.0 == player) {
+                let (_, waiting) = series.waiting.remove(i);
+                self.metrics.queue_wait.observe(waiting.since.elapsed());
+                let _ = waiting.reply.send(announcement.clone());
+            }
+        }
+    }
+
+    /// The opponents of the player do not wait for it any longer
+    fn start_match_games_of(&self, nickname: &str) {
+        let mut matches = self.matches.lock().unwrap();
+        for series in matches.values_mut().filter(|s| s.players.iter().any(|p| p == nickname)) {
+            self.start_match_game(series);
+        }
+    }
+
+    /// Wait in the lobby until two full teams want to play
+    fn find_or_create_team_game(&self, nickname: String, team: u64) -> oneshot::Receiver<ParsedLine> {
+        let (tx, rx) = oneshot::channel();
+        let mut lobby = self.team_lobby.lock().unwrap();
+        lobby.retain(|w| !w.2.is_gone());
+        lobby.push((nickname, team, Waiting::new(tx)));
+
+        // The first members of every team, nobody plays twice
+        let mut teams: Vec<(u64, Vec<usize>)> = Vec::new();
+        for (i, &(ref name, team, _)) in lobby.iter().enumerate() {
+            if teams.iter().any(|t| t.1.iter().any(|&j| lobby[j].0 == *name)) {
+                continue
+            }
+            match teams.iter().position(|t| t.0 == team) {
+                Some(t) => teams[t].1.push(i),
+                None => teams.push((team, vec![i])),
+            }
+        }
+        let full: Vec<Vec<usize>> = teams.into_iter()
+            .map(|t| t.1)
+            .filter(|members| members.len() >= TEAM_SIZE)
+            .take(2)
+            .collect();
+        if full.len() < 2 {
+            return rx
+        }
+
+        let mut picked: Vec<usize> = full.iter().flat_map(|members| members.iter().take(TEAM_SIZE).cloned()).collect();
+        picked.sort();
+        let mut players = Vec::new();
+        for i in picked.into_iter().rev() {
+            players.insert(0, lobby.remove(i));
+        }
+
+        let state = GameState::with_teams(self.next_game_id(), players.iter().map(|p| (p.0.clone(), p.1)).collect(), self.settings().friendly_fire);
+        self.announce(state, players.into_iter().map(|p| p.2).collect());
+        rx
+    }
+
+    /// Refused unless the game runs and the move is valid in its current round
+    fn play(&self, game_id: u64, mv: PlayerMove) -> Result<oneshot::Receiver<Action>, Violation> {
+        let (rx, finished) = {
+            let mut arenas = self.arenas.lock(game_id);
+            let arena = arenas.get_mut(&game_id).ok_or(Violation::UnknownGame(game_id))?;
+            let (latency, rounds) = (arena.round_started.elapsed(), arena.rounds.len());
+            let rx = arena.submit(mv)?;
+            self.metrics.round_latency.observe(latency);
+            self.rounds_resolved(arena, rounds);
+            self.push_outcomes(arena);
+            let finished = if arenas[&game_id].state.is_over() { arenas.remove(&game_id) } else { None };
+            (rx, finished)
+        };
+
+        if let Some(arena) = finished {
+            self.finish_arena(arena);
+        }
+        Ok(rx)
+    }
+
+    /// Wins and losses by nickname
+    fn statistics(&self) -> HashMap<String, (u64, u64)> {
+        self.player_statistics.lock().unwrap().clone()
+    }
+
+    /// What `f` takes from every running game, by game id
+    ///
+    /// Only one shard is locked at a time, so the games in the others go on meanwhile.
+    fn live_games<T, F: Fn(&Arena) -> Option<T>>(&self, f: F) -> Vec<T> {
+        let mut live: Vec<(u64, T)> = Vec::new();
+        for arenas in self.arenas.each() {
+            live.extend(arenas.values().filter_map(|a| f(a).map(|t| (a.state.game_id, t))));
+        }
+        live.sort_by_key(|l| l.0);
+        live.into_iter().map(|l| l.1).collect()
+    }
+
+    /// The running games the player is in
+    fn live_games_of(&self, nickname: &str) -> Vec<u64> {
+        self.live_games(|a| a.state.player(nickname).map(|_| a.state.game_id))
+    }
+
+    /// Look at the recently finished games, oldest first
+    fn with_finished_games<R, F: FnOnce(Vec<&Arena>) -> R>(&self, f: F) -> R {
+        let finished = self.finished.lock().unwrap();
+        f(finished.iter().collect())
+    }
+
+    /// Look at a running or recently finished game, the flag tells if it is still running
+    fn with_game<R, F: FnOnce(&Arena, bool) -> R>(&self, game_id: u64, f: F) -> Option<R> {
+        if let Some(arena) = self.arenas.lock(game_id).get(&game_id) {
+            return Some(f(arena, true))
+        }
+        self.finished.lock().unwrap().iter().find(|a| a.state.game_id == game_id).map(|arena| f(arena, false))
+    }
+
+    fn finish_arena(&self, arena: Arena) {
+        let outcome = match (arena.state.winning_team(), arena.state.winner()) {
+            (Some(_), _) => "team_win",
+            (None, Some(_)) => "win",
+            (None, None) => "draw",
+        };
+        info!(game_id = arena.state.game_id, outcome, winner = ?arena.state.winner(), rounds = arena.rounds.len(), "game finished");
+        self.metrics.games_finished.inc(outcome);
+        {
+            let mut stats = self.player_statistics.lock().unwrap();
+            for player in &arena.state.players {
+                let entry = stats.entry(player.nickname.clone()).or_insert((0, 0));
+                if arena.state.has_won(&player.nickname) {
+                    entry.0 += 1;
+                } else {
+                    entry.1 += 1;
+                }
+            }
+            let mut team_stats = self.team_statistics.lock().unwrap();
+            for team in arena.state.teams() {
+                let entry = team_stats.entry(team).or_insert((0, 0));
+                if arena.state.winning_team() == Some(team) {
+                    entry.0 += 1;
+                } else {
+                    entry.1 += 1;
+                }
+            }
+        }
+
+        if let Some(match_id) = arena.match_id {
+            self.continue_match(match_id, &arena.state);
+        }
+        self.keep_finished(arena);
+    }
+
+    fn keep_finished(&self, arena: Arena) {
+        let mut finished = self.finished.lock().unwrap();
+        finished.push_back(arena);
+        if finished.len() > KEPT_GAMES {
+            finished.pop_front();
+        }
+    }
+
+    fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Running games the player is still in, plus the lobbies and challenges it waits in
+    fn games_of(&self, nickname: &str) -> usize {
+        let playing: usize = self.arenas.each()
+            .map(|arenas| arenas.values().filter(|a| a.state.player(nickname).map(|p| p.alive) == Some(true)).count())
+            .sum();
+        let lobby = self.lobby.lock().unwrap().values().flatten().filter(|w| w.0 == nickname).count();
+        let match_lobby = self.match_lobby.lock().unwrap().values().flatten().filter(|w| w.0 == nickname).count();
+        let team_lobby = self.team_lobby.lock().unwrap().iter().filter(|w| w.0 == nickname).count();
+        let challenges = self.challenges.lock().unwrap().values().filter(|c| c.challenger == nickname).count();
+        playing + lobby + match_lobby + team_lobby + challenges
+    }
+
+    /// Count a new game connection from `ip`, false if that is one too many
+    fn open_from(&self, ip: IpAddr) -> bool {
+        let mut connections = self.connections_by_ip.lock().unwrap();
+        let open = connections.entry(ip).or_insert(0);
+        *open += 1;
+        !limits::exceeds(*open, self.settings().limits.connections_per_ip)
+    }
+
+    fn close_from(&self, ip: IpAddr) {
+        let mut connections = self.connections_by_ip.lock().unwrap();
+        if let Some(open) = connections.get_mut(&ip) {
+            *open -= 1;
+            if *open == 0 {
+                connections.remove(&ip);
+            }
+        }
+    }
+
+    /// Stop matchmaking and give the running games up to `grace` to finish, the rest ends with `ShuttingDown`
+    fn shutdown(&self, grace: Duration) {
+        self.draining.store(true, Ordering::SeqCst);
+        // Dropping the replies cancels everyone still waiting for an opponent
+        self.lobby.lock().unwrap().clear();
+        self.match_lobby.lock().unwrap().clear();
+        self.team_lobby.lock().unwrap().clear();
+        self.challenges.lock().unwrap().clear();
+
+        let deadline = Instant::now() + grace;
+        while self.arenas.each().any(|arenas| !arenas.is_empty()) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(100));
+        }
+        let aborted: Vec<Arena> = self.arenas.each().flat_map(|mut arenas| arenas.drain().map(|a| a.1).collect::<Vec<_>>()).collect();
+        for mut arena in aborted {
+            warn!(game_id = arena.state.game_id, "game aborted");
+            arena.abort(ErrorCode::ShuttingDown, "the server is shutting down");
+            self.push_outcomes(&mut arena);
+            self.keep_finished(arena);
+        }
+    }
+
+    /// Close every connection of the player and forfeit its running games, false if it neither is connected nor plays
+    fn kick(&self, nickname: &str) -> bool {
+        let connected = self.push(nickname, ParsedLine::Error { code: ErrorCode::Kicked, message: "kicked by an admin".into() });
+        // Without a session there is nothing to resume
+        self.sessions.lock().unwrap().retain(|_, s| s.nickname != nickname);
+        let playing = !self.live_games_of(nickname).is_empty();
+        self.with_games_of(nickname, |arena| arena.forfeit(nickname));
+        info!(%nickname, connected, playing, "kicked");
+        connected || playing
+    }
+
+    /// End a running game without counting it, false if there is no such game
+    fn abort(&self, game_id: u64, message: &str) -> bool {
+        let mut arena = match self.arenas.lock(game_id).remove(&game_id) {
+            Some(arena) => arena,
+            None => return false,
+        };
+        warn!(game_id, %message, "game aborted");
+        arena.abort(ErrorCode::Aborted, message);
+        self.drop_game(arena, message);
+        true
+    }
+
+    /// Hand out the outcomes of a game that ended early and keep it without counting it
+    fn drop_game(&self, mut arena: Arena, message: &str) {
+        self.push_outcomes(&mut arena);
+        // The series can not go on without this game
+        if let Some(series) = arena.match_id.and_then(|match_id| self.matches.lock().unwrap().remove(&match_id)) {
+            for (_, waiting) in series.waiting {
+                let _ = waiting.reply.send(MultiplexedMessage { game_id: series.match_id, action: Action::Error { code: ErrorCode::Aborted, message: message.into() } });
+            }
+        }
+        self.keep_finished(arena);
+    }
+
+    /// Overwrite the wins and losses of a player, zero for both forgets it
+    fn set_statistics(&self, nickname: &str, won: u64, lost: u64) {
+        let mut stats = self.player_statistics.lock().unwrap();
+        if won + lost == 0 {
+            stats.remove(nickname);
+        } else {
+            stats.insert(nickname.to_string(), (won, lost));
+        }
+    }
+
+    fn reset_statistics(&self) {
+        self.player_statistics.lock().unwrap().clear();
+        self.team_statistics.lock().unwrap().clear();
+    }
+
+    /// Everybody plays a series of `games` against everybody else, returns the match ids
+    ///
+    /// Each player gets the `NewMatch` of all its series, then plays them one after the other on `RequestNewGame`.
+    fn start_tournament(&self, players: &[String], games: u64) -> Vec<u64> {
+        let mut match_ids = Vec::new();
+        for (i, a) in players.iter().enumerate() {
+            for b in &players[i + 1..] {
+                let series = MatchSeries::new(self.next_game_id(), games, [a.clone(), b.clone()]);
+                let match_id = series.match_id;
+                for player in &series.players {
+                    self.push_pending(player, MultiplexedMessage { game_id: match_id, action: Action::NewMatch { games } });
+                }
+                self.matches.lock().unwrap().insert(match_id, series);
+                match_ids.push(match_id);
+            }
+        }
+        info!(?players, games, matches = ?match_ids, "tournament started");
+        match_ids
+    }
+
+    /// Nicknames with the ids of their open connections
+    fn connected(&self) -> Vec<(String, Vec<usize>)> {
+        let mut connected: Vec<(String, Vec<usize>)> = self.connections.lock().unwrap().iter()
+            .map(|(nickname, open)| (nickname.clone(), open.iter().map(|c| c.0).collect()))
+            .collect();
+        connected.sort();
+        connected
+    }
+
+    /// Write the leaderboard and the replays of the finished games as JSON
+    fn save(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)?;
+        fs::write(dir.join("statistics.json"), api::players(self).to_string())?;
+        let replays = self.with_finished_games(|arenas| Json::Array(arenas.into_iter().map(api::replay).collect()));
+        fs::write(dir.join("replays.json"), replays.to_string())
+    }
+
+    /// Start the next game of the series, or tell both players the final score
+    fn continue_match(&self, match_id: u64, game: &GameState) {
+        let mut matches = self.matches.lock().unwrap();
+        let over = match matches.get_mut(&match_id) {
+            Some(series) => {
+                series.record(game);
+                series.is_over()
+            }
+            None => return,
+        };
+        if over {
+            let mut series = matches.remove(&match_id).unwrap();
+            info!(match_id, "match over");
+            for player in series.players.clone() {
+                let result = MultiplexedMessage { game_id: match_id, action: series.result_for(&player) };
+                // Whoever asked for the next game already gets the result right away
+                match series.waiting.iter().position(|w| w.0 == player) {
+                    Some(i) => { let _ = series.waiting.remove(i).1.reply.send(result); },
+                    None => self.push_pending(&player, result),
+                }
+            }
+        } else if self.is_draining() {
+            matches.remove(&match_id);
+        } else {
+            self.start_match_game(matches.get_mut(&match_id).unwrap());
+        }
+    }
 }
 
-use tokio_service::Service;
-use futures::{future, Future, BoxFuture};
+/// Take the first `size` waiting players with different nicknames, nobody plays against themselves
+fn pick_players<T>(queue: &mut Vec<(String, T)>, size: usize) -> Option<Vec<(String, T)>> {
+    let mut picked: Vec<usize> = Vec::new();
+    for (i, (name, _)) in queue.iter().enumerate() {
+        if !picked.iter().any(|&j| queue[j].0 == *name) {
+            picked.push(i);
+        }
+    }
+    if picked.len() < size {
+        return None
+    }
 
-impl Service for ArenaService {
-    // These types must match the corresponding protocol types:
-    type Request = ParsedLine;
-    type Response = ParsedLine;
+    let mut players = Vec::new();
+    for i in picked.into_iter().take(size).rev() {
+        players.insert(0, queue.remove(i));
+    }
+    Some(players)
+}
 
-    // For non-streaming protocols, service errors are always io::Error
-    type Error = io::Error;
+impl ArenaService {
+    fn new(server: Arc<ArenaServer>, outbox: Outbox) -> ArenaService {
+        server.metrics.connections.inc();
+        server.metrics.open_connections.inc();
+        let id = server.next_connection_id.fetch_add(1, Ordering::SeqCst);
+        let span = info_span!("connection", id, nickname = field::Empty);
+        info!(parent: &span, "connection opened");
+        let new_games = Mutex::new(RateLimiter::new());
+        ArenaService {
+            server,
+            id,
+            outbox,
+            nickname: Mutex::new(None),
+            session: Mutex::new(None),
+            new_games,
+            span,
+        }
+    }
 
-    // The future for computing the response; box it for simplicity.
-    type Future = BoxFuture<Self::Response, Self::Error>;
+    /// Finish the handshake as `nickname` in the session
+    fn accept(&self, nickname: String, session: String) -> BoxFuture<'static, io::Result<ParsedLine>> {
+        self.server.connect(&nickname, self.id, self.outbox.clone());
+        self.server.metrics.handshakes.inc("succeeded");
+        self.span.record("nickname", nickname.as_str());
+        info!("handshake accepted");
+        *self.nickname.lock().unwrap() = Some(nickname);
+        *self.session.lock().unwrap() = Some(session.clone());
+        future::ok(ServerHello {
+            max_round_length: self.server.settings().max_round_length,
+            session: Some(session),
+        }).boxed()
+    }
 
-    // Produce a future for computing a response from a request.
-    fn call(&self, req: Self::Request) -> Self::Future {
-        println!("call: {:?}", req);
+    fn nickname(&self) -> Result<String, ParsedLine> {
+        self.nickname.lock().unwrap().clone().ok_or_else(|| self.refusal(0, Violation::NoHandshake))
+    }
 
+    /// The nickname, unless the server is shutting down and starts no new games
+    ///
+    /// A player over the limits is refused on game id 0, the connection stays open.
+    fn nickname_for_new_game(&self) -> Result<String, ParsedLine> {
+        if self.server.is_draining() {
+            return Err(ParsedLine::Error { code: ErrorCode::ShuttingDown, message: "the server is shutting down".into() })
+        }
+        let nickname = self.nickname()?;
+        let limits = self.server.settings().limits;
+        let limited = if !self.new_games.lock().unwrap().allow(Instant::now(), limits.new_games_per_second) {
+            Some(("new_games_per_second", "too many new game requests"))
+        } else if limits::exceeds(self.server.games_of(&nickname) + 1, limits.games_per_nickname) {
+            Some(("games_per_nickname", "too many games at the same time"))
+        } else {
+            None
+        };
+        match limited {
+            Some((limit, message)) => {
+                warn!(limit, "rate limited");
+                self.server.metrics.rate_limited.inc(limit);
+                Err(MultiplexedMessage { game_id: 0, action: Action::Error { code: ErrorCode::RateLimited, message: message.into() } })
+            }
+            None => Ok(nickname),
+        }
+    }
+
+    /// A fatal violation closes the connection with an `Error`, otherwise the request is answered with one on its game id
+    fn refusal(&self, game_id: u64, violation: Violation) -> ParsedLine {
+        warn!(%violation, "protocol violation");
+        let (code, message) = (violation.code(), violation.to_string());
+        if violation.is_fatal() {
+            ParsedLine::Error { code, message }
+        } else {
+            MultiplexedMessage { game_id, action: Action::Error { code, message } }
+        }
+    }
+
+    fn refuse(&self, game_id: u64, violation: Violation) -> BoxFuture<'static, io::Result<ParsedLine>> {
+        future::ok(self.refusal(game_id, violation)).boxed()
+    }
+
+    fn request_team_game(&self, team: u64) -> BoxFuture<'static, io::Result<ParsedLine>> {
+        let nickname = match self.nickname_for_new_game() {
+            Ok(n) => n,
+            Err(line) => return future::ok(line).boxed(),
+        };
+        self.server.find_or_create_team_game(nickname, team)
+            .map(|reply| Ok(reply.unwrap_or_else(|_| canceled("matchmaking canceled"))))
+            .boxed()
+    }
+
+    /// Continue a match series or wait for a new opponent
+    fn request_new_game(&self) -> BoxFuture<'static, io::Result<ParsedLine>> {
+        let nickname = match self.nickname() {
+            Ok(n) => n,
+            Err(line) => return future::ok(line).boxed(),
+        };
+        if let Some(line) = self.server.take_pending(&nickname) {
+            return future::ok(line).boxed()
+        }
+        match self.server.next_match_game(&nickname) {
+            Some(reply) => reply.map(|reply| Ok(reply.unwrap_or_else(|_| canceled("match canceled")))).boxed(),
+            None => self.request_arena(2),
+        }
+    }
+
+    fn challenge(&self, opponent: String) -> BoxFuture<'static, io::Result<ParsedLine>> {
+        let nickname = match self.nickname_for_new_game() {
+            Ok(n) => n,
+            Err(line) => return future::ok(line).boxed(),
+        };
+        self.server.challenge(nickname, opponent)
+            .map(|reply| Ok(reply.unwrap_or_else(|_| canceled("challenge canceled"))))
+            .boxed()
+    }
+
+    fn answer_challenge(&self, game_id: u64, accept: bool) -> BoxFuture<'static, io::Result<ParsedLine>> {
+        let nickname = match self.nickname() {
+            Ok(n) => n,
+            Err(line) => return future::ok(line).boxed(),
+        };
+        match self.server.answer_challenge(&nickname, game_id, accept) {
+            Ok(line) => future::ok(line).boxed(),
+            Err(violation) => self.refuse(game_id, violation),
+        }
+    }
+
+    fn request_match(&self, games: u64) -> BoxFuture<'static, io::Result<ParsedLine>> {
+        if games < 1 {
+            return self.refuse(0, Violation::NoGames)
+        }
+        let nickname = match self.nickname_for_new_game() {
+            Ok(n) => n,
+            Err(line) => return future::ok(line).boxed(),
+        };
+        self.server.find_or_create_match(nickname, games)
+            .map(|reply| Ok(reply.unwrap_or_else(|_| canceled("matchmaking canceled"))))
+            .boxed()
+    }
+
+    fn request_arena(&self, size: u64) -> BoxFuture<'static, io::Result<ParsedLine>> {
+        if size < 2 {
+            return self.refuse(0, Violation::TooFewPlayers(size))
+        }
+        let nickname = match self.nickname_for_new_game() {
+            Ok(n) => n,
+            Err(line) => return future::ok(line).boxed(),
+        };
+        self.server.find_or_create_arena(nickname, size)
+            .map(|reply| Ok(reply.unwrap_or_else(|_| canceled("matchmaking canceled"))))
+            .boxed()
+    }
+
+    fn play(&self, game_id: u64, action: RoundAction, target: Option<String>) -> BoxFuture<'static, io::Result<ParsedLine>> {
+        let player = match self.nickname() {
+            Ok(n) => n,
+            Err(line) => return future::ok(line).boxed(),
+        };
+        match self.server.play(game_id, PlayerMove { player, action, target }) {
+            Ok(rx) => rx
+                .map_ok(move |action| MultiplexedMessage { game_id, action })
+                .map_err(|_| io::Error::other("game canceled"))
+                .boxed(),
+            Err(violation) => self.refuse(game_id, violation),
+        }
+    }
+
+    /// The reply to one request, everything else is pushed through the outbox
+    fn call(&self, req: ParsedLine) -> BoxFuture<'static, io::Result<ParsedLine>> {
+        let _connection = self.span.enter();
+        let game = match req {
+            MultiplexedMessage { game_id, .. } => info_span!("game", game_id),
+            _ => Span::none(),
+        };
+        let _game = game.enter();
+        debug!(request = ?req, "request");
+
+        let greeted = self.nickname.lock().unwrap().is_some();
         match req {
-            MultiplexedMessage { game_id, action } => {
-                let resp = MultiplexedMessage {
-                    game_id: game_id,
-                    action: Action::PlayerInput(RoundAction::Load),
-                };
-                // In this case, the response is immediate.
-                future::ok(resp).boxed()
+            ClientHello { .. } | Resume { .. } if greeted => self.refuse(0, Violation::RepeatedHandshake),
+            ClientHello { nickname, token, .. } => {
+                if let Err(reason) = self.server.authenticate(&nickname, token.as_deref()) {
+                    warn!(%nickname, %reason, "handshake rejected");
+                    self.server.metrics.handshakes.inc("failed");
+                    return future::ok(HandshakeRejected { reason }).boxed()
+                }
+                let session = self.server.open_session(&nickname);
+                self.accept(nickname, session)
+            }
+            Resume { token } => {
+                match self.server.resume(&token) {
+                    Some(nickname) => {
+                        info!(%nickname, "session resumed");
+                        self.accept(nickname, token)
+                    }
+                    None => {
+                        warn!("resume of an unknown session rejected");
+                        self.server.metrics.handshakes.inc("failed");
+                        future::ok(HandshakeRejected { reason: "unknown or expired session".into() }).boxed()
+                    }
+                }
             }
-            RequestNewGame => {
-                //self.server.find_or_create_arena()
-                let resp = MultiplexedMessage {
-                    game_id: 0,
-                    action: Action::NewGame { player_name_a: "me".into(), player_name_b: "you".into() },
-                };
-                future::ok(resp).boxed()
+            MultiplexedMessage { game_id, action: Action::PlayerInput(action) } => {
+                self.play(game_id, action, None)
             }
-            _ => future::err(io::Error::new(io::ErrorKind::Other, "invalid client state")).boxed()
+            MultiplexedMessage { game_id, action: Action::TargetedInput { action, target } } => {
+                self.play(game_id, action, Some(target))
+            }
+            RequestNewGame => self.request_new_game(),
+            RequestNewArena { players } => self.request_arena(players),
+            RequestTeamGame { team } => self.request_team_game(team),
+            RequestNewMatch { games } => self.request_match(games),
+            Challenge { nickname } => self.challenge(nickname),
+            MultiplexedMessage { game_id, action: Action::ChallengeAccepted } => self.answer_challenge(game_id, true),
+            MultiplexedMessage { game_id, action: Action::ChallengeDeclined } => self.answer_challenge(game_id, false),
+            other => self.refuse(0, Violation::ServerMessage(other)),
+        }
+    }
+}
+
+/// Waiting for an opponent or a challenge only ends without an answer when the server shuts down
+fn canceled(message: &str) -> ParsedLine {
+    ParsedLine::Error { code: ErrorCode::ShuttingDown, message: message.into() }
+}
+
+impl Drop for ArenaService {
+    fn drop(&mut self) {
+        info!(parent: &self.span, "connection closed");
+        self.server.metrics.open_connections.dec();
+        if let Some(ref session) = *self.session.lock().unwrap() {
+            self.server.close_session(session);
+        }
+        if let Some(ref nickname) = *self.nickname.lock().unwrap() {
+            self.server.disconnect(nickname, self.id);
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::channel::mpsc::TryRecvError;
+
+    #[test]
+    fn pick_different_players() {
+        let mut queue = vec![("a".to_string(), 1), ("a".to_string(), 2), ("b".to_string(), 3)];
+        assert_eq!(Some(vec![("a".to_string(), 1), ("b".to_string(), 3)]), pick_players(&mut queue, 2));
+        assert_eq!(vec![("a".to_string(), 2)], queue);
+        assert_eq!(None, pick_players(&mut queue, 2));
+    }
+
+    #[test]
+    fn challenge_accepted() {
+        let server = ArenaServer::new(Settings::default());
+        let _a = connect(&server, "a");
+        let mut b = connect(&server, "b");
+        let reply = server.challenge("a".into(), "b".into());
+        let challenged = b.try_recv().unwrap();
+        let game_id = match challenged {
+            MultiplexedMessage { game_id, action: Action::ChallengedBy { ref nickname } } if nickname == "a" => game_id,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(Err(Violation::UnknownChallenge(game_id)), server.answer_challenge("a", game_id, true));
+        let new_game = MultiplexedMessage { game_id, action: Action::NewGame { player_name_a: "a".into(), player_name_b: "b".into() } };
+        assert_eq!(Ok(new_game.clone()), server.answer_challenge("b", game_id, true));
+        assert_eq!(Ok(new_game), block_on(reply));
+    }
+
+    #[test]
+    fn house_bot_steps_in() {
+        let server = ArenaServer::new(Settings {
+            house_bot: Some(Strategy::Coward),
+            house_bot_wait: Duration::from_millis(0),
+            ..Settings::default()
+        });
+        let reply = server.find_or_create_arena("a".into(), 2);
+        server.house_keeping();
+        match block_on(reply) {
+            Ok(MultiplexedMessage { action: Action::NewGame { ref player_name_b, .. }, .. }) => assert_eq!("house:coward", player_name_b),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn registered_nickname_needs_token() {
+        let path = std::env::temp_dir().join(format!("shotgun_gameserver_registry_{}.txt", std::process::id()));
+        let mut registry = Registry::new();
+        registry.register("a", "secret");
+        registry.save(&path).unwrap();
+        let server = ArenaServer::new(Settings { registry: Some(path.clone()), ..Settings::default() });
+
+        assert!(server.authenticate("a", None).is_err());
+        assert!(server.authenticate("a", Some("guess")).is_err());
+        assert_eq!(Ok(()), server.authenticate("a", Some("secret")));
+        assert_eq!(Ok(()), server.authenticate("b", None));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn shutdown_aborts_running_games() {
+        let server = ArenaServer::new(Settings::default());
+        let _waiting = server.find_or_create_arena("a".into(), 2);
+        let _b = server.find_or_create_arena("b".into(), 2);
+        let waiting = server.find_or_create_arena("c".into(), 2);
+        let game_id = server.live_games(|a| Some(a.state.game_id))[0];
+        let a = server.play(game_id, PlayerMove { player: "a".into(), action: RoundAction::Load, target: None }).unwrap();
+
+        server.shutdown(Duration::from_millis(0));
+        match block_on(a) {
+            Ok(Action::Error { code: ErrorCode::ShuttingDown, .. }) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(block_on(waiting).is_err());
+        assert!(server.with_game(game_id, |_, live| live) == Some(false));
+
+        let dir = std::env::temp_dir().join(format!("shotgun_gameserver_data_{}", std::process::id()));
+        server.save(&dir).unwrap();
+        assert!(fs::read_to_string(dir.join("replays.json")).unwrap().contains(&format!(r#""game_id":{}"#, game_id)));
+        assert!(dir.join("statistics.json").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A connection of the player, everything pushed to it arrives at the receiver
+    fn connect(server: &ArenaServer, nickname: &str) -> mpsc::Receiver<ParsedLine> {
+        let (outbox, pushed) = mpsc::channel(OUTBOX_SIZE);
+        server.connect(nickname, 0, outbox);
+        pushed
+    }
+
+    fn start_duel(server: &ArenaServer) -> u64 {
+        let _a = connect(server, "a");
+        let _b = connect(server, "b");
+        let _waiting = server.find_or_create_arena("a".into(), 2);
+        let _b = server.find_or_create_arena("b".into(), 2);
+        server.live_games(|a| Some(a.state.game_id))[0]
+    }
+
+    fn mv(player: &str, action: RoundAction) -> PlayerMove {
+        PlayerMove { player: player.into(), action, target: None }
+    }
+
+    #[test]
+    fn match_game_starts_when_both_ask() {
+        let server = ArenaServer::new(Settings::default());
+        let _a = connect(&server, "a");
+        let _b = connect(&server, "b");
+        let _waiting = server.find_or_create_match("a".into(), 1);
+        let _b = server.find_or_create_match("b".into(), 1);
+        let a = server.next_match_game("a").unwrap();
+        assert_eq!(0, server.live_games(|_| Some(())).len());
+        let b = server.next_match_game("b").unwrap();
+        let (a, b) = (block_on(a).unwrap(), block_on(b).unwrap());
+        assert_eq!(a, b);
+        let game_id = match a {
+            MultiplexedMessage { game_id, action: Action::NewGame { .. } } => game_id,
+            other => panic!("unexpected {:?}", other),
+        };
+
+        // Asking again while the game runs waits for its end, here the end of the series
+        let result = server.next_match_game("a").unwrap();
+        for (a, b) in [(RoundAction::Load, RoundAction::Load), (RoundAction::Shoot, RoundAction::Load)] {
+            let _a = server.play(game_id, mv("a", a)).unwrap();
+            let _b = server.play(game_id, mv("b", b)).unwrap();
+        }
+        match block_on(result) {
+            Ok(MultiplexedMessage { action: Action::MatchResult { won: 1, lost: 0 }, .. }) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+        match server.take_pending("b") {
+            Some(MultiplexedMessage { action: Action::MatchResult { won: 0, lost: 1 }, .. }) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(server.next_match_game("a").is_none());
+    }
+
+    #[test]
+    fn round_times_out() {
+        let server = ArenaServer::new(Settings { max_round_length: Duration::from_millis(1), ..Settings::default() });
+        let game_id = start_duel(&server);
+        let a = server.play(game_id, mv("a", RoundAction::Load)).unwrap();
+        thread::sleep(Duration::from_millis(2));
+        server.time_out_rounds(Duration::from_millis(0));
+        assert_eq!(Ok(Action::RoundResult { a: RoundAction::Load, b: RoundAction::Timeout }), block_on(a));
+        assert!(server.metrics.render().contains("shotgun_timeouts_total{nickname=\"b\"} 1\n"));
+    }
+
+    #[test]
+    fn silent_game_is_abandoned() {
+        let server = ArenaServer::new(Settings { max_round_length: Duration::from_millis(1), ..Settings::default() });
+        let game_id = start_duel(&server);
+        thread::sleep(Duration::from_millis(2));
+        server.time_out_rounds(Duration::from_millis(0));
+        assert!(server.play(game_id, mv("a", RoundAction::Load)).is_err());
+        assert!(server.statistics().is_empty());
+        assert_eq!(1, server.finished.lock().unwrap().len());
+    }
+
+    #[test]
+    fn absent_players_keep_their_game() {
+        let server = ArenaServer::new(Settings { max_round_length: Duration::from_millis(1), ..Settings::default() });
+        let game_id = start_duel(&server);
+        server.with_games_of("a", |arena| { arena.set_absent("a", true); arena.set_absent("b", true) });
+        thread::sleep(Duration::from_millis(2));
+        server.time_out_rounds(Duration::from_millis(0));
+        assert!(server.play(game_id, mv("a", RoundAction::Load)).is_ok());
+    }
+
+    #[test]
+    fn resume_within_grace() {
+        let server = ArenaServer::new(Settings::default());
+        let token = server.open_session("a");
+        let game_id = start_duel(&server);
+        server.close_session(&token);
+        server.disconnect("a", 0);
+        let b = server.play(game_id, mv("b", RoundAction::Load)).unwrap();
+        assert_eq!(Ok(Action::RoundResult { a: RoundAction::Timeout, b: RoundAction::Load }), block_on(b));
+
+        assert_eq!(Some("a".to_string()), server.resume(&token));
+        let _a = connect(&server, "a");
+        let _b = server.play(game_id, mv("b", RoundAction::Duck)).unwrap();
+        let a = server.play(game_id, mv("a", RoundAction::Load)).unwrap();
+        assert_eq!(Ok(Action::RoundResult { a: RoundAction::Load, b: RoundAction::Duck }), block_on(a));
+    }
+
+    #[test]
+    fn outcome_is_pushed_after_the_last_result() {
+        let server = ArenaServer::new(Settings::default());
+        let mut a = connect(&server, "a");
+        let mut b = connect(&server, "b");
+        let _waiting = server.find_or_create_arena("a".into(), 2);
+        let _b = server.find_or_create_arena("b".into(), 2);
+        let game_id = server.live_games(|a| Some(a.state.game_id))[0];
+        let _a = server.play(game_id, mv("a", RoundAction::Load));
+        let _b = server.play(game_id, mv("b", RoundAction::Load));
+        let _a = server.play(game_id, mv("a", RoundAction::Shoot));
+        assert_eq!(Err(TryRecvError::Empty), a.try_recv());
+        let reply = server.play(game_id, mv("b", RoundAction::Load)).unwrap();
+
+        assert_eq!(Ok(Action::RoundResult { a: RoundAction::Shoot, b: RoundAction::Load }), block_on(reply));
+        assert_eq!(Ok(MultiplexedMessage { game_id, action: Action::WinGame }), a.try_recv());
+        assert_eq!(Ok(MultiplexedMessage { game_id, action: Action::LoseGame }), b.try_recv());
+    }
+
+    #[test]
+    fn expired_session_forfeits() {
+        let server = ArenaServer::new(Settings { resume_grace: Duration::from_millis(0), ..Settings::default() });
+        let token = server.open_session("a");
+        let game_id = start_duel(&server);
+        server.close_session(&token);
+        server.disconnect("a", 0);
+        server.house_keeping();
+        assert_eq!(None, server.resume(&token));
+        assert_eq!(Some(false), server.with_game(game_id, |_, live| live));
+        assert_eq!(Some(&(1, 0)), server.statistics().get("b"));
+    }
+
+    #[test]
+    fn challenge_absent_player() {
+        let server = ArenaServer::new(Settings::default());
+        let _a = connect(&server, "a");
+        match block_on(server.challenge("a".into(), "b".into())) {
+            Ok(MultiplexedMessage { action: Action::ChallengeDeclined, .. }) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn disconnect_declines_challenge() {
+        let server = ArenaServer::new(Settings::default());
+        let _a = connect(&server, "a");
+        let _b = connect(&server, "b");
+        let reply = server.challenge("a".into(), "b".into());
+        server.disconnect("b", 0);
+        match block_on(reply) {
+            Ok(MultiplexedMessage { action: Action::ChallengeDeclined, .. }) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
     #[test]
-    fn it_works() {
+    fn protocol_violations() {
+        let server = ArenaServer::new(Settings::default());
+        let game_id = start_duel(&server);
+        let (outbox, _pushed) = mpsc::channel(OUTBOX_SIZE);
+        let service = ArenaService::new(server, outbox);
+        let closed = |code| ParsedLine::Error { code, message: String::new() };
+        let without_message = |line: ParsedLine| match line {
+            ParsedLine::Error { code, .. } => closed(code),
+            MultiplexedMessage { game_id, action: Action::Error { code, .. } } => MultiplexedMessage { game_id, action: Action::Error { code, message: String::new() } },
+            other => other,
+        };
+        let call = |request| without_message(block_on(service.call(request)).unwrap());
+
+        assert_eq!(closed(ErrorCode::InvalidHandshake), call(RequestNewGame));
+        let hello = ClientHello { nickname: "c".into(), programming_language: "rust".into(), token: None };
+        assert!(block_on(service.call(hello.clone())).is_ok());
+        assert_eq!(closed(ErrorCode::InvalidHandshake), call(hello));
+
+        let not_mine = MultiplexedMessage { game_id, action: Action::PlayerInput(RoundAction::Load) };
+        let refused = MultiplexedMessage { game_id, action: Action::Error { code: ErrorCode::UnknownGame, message: String::new() } };
+        assert_eq!(refused, call(not_mine));
+        assert_eq!(closed(ErrorCode::UnexpectedMessage), call(MultiplexedMessage { game_id, action: Action::WinGame }));
+        // Only refused, the connection stays open
+        let invalid = MultiplexedMessage { game_id: 0, action: Action::Error { code: ErrorCode::InvalidRequest, message: String::new() } };
+        assert_eq!(invalid, call(RequestNewArena { players: 1 }));
+        assert_eq!(invalid, call(RequestNewMatch { games: 0 }));
+    }
+
+    #[test]
+    fn limits_refuse_new_games() {
+        let limits = Limits { games_per_nickname: 1, new_games_per_second: 2, ..Limits::default() };
+        let server = ArenaServer::new(Settings { limits, ..Settings::default() });
+        let (outbox, _pushed) = mpsc::channel(OUTBOX_SIZE);
+        let service = ArenaService::new(server.clone(), outbox);
+        let hello = ClientHello { nickname: "c".into(), programming_language: "rust".into(), token: None };
+        assert!(block_on(service.call(hello)).is_ok());
+
+        let _waiting = service.call(RequestNewArena { players: 3 });
+        for limit in &["games_per_nickname", "new_games_per_second"] {
+            match block_on(service.call(RequestNewArena { players: 3 })) {
+                Ok(MultiplexedMessage { game_id: 0, action: Action::Error { code: ErrorCode::RateLimited, .. } }) => {},
+                other => panic!("unexpected {:?}", other),
+            }
+            assert!(server.metrics.render().contains(&format!("shotgun_rate_limited_total{{limit=\"{}\"}} 1\n", limit)));
+        }
+    }
+
+    #[test]
+    fn limits_connections_per_ip() {
+        let limits = Limits { connections_per_ip: 2, ..Limits::default() };
+        let server = ArenaServer::new(Settings { limits, ..Settings::default() });
+        let ip: IpAddr = "::1".parse().unwrap();
+        assert!(server.open_from(ip));
+        assert!(server.open_from(ip));
+        assert!(!server.open_from(ip));
+        assert!(server.open_from("127.0.0.1".parse().unwrap()));
+        server.close_from(ip);
+        server.close_from(ip);
+        assert!(server.open_from(ip));
+    }
+
+    #[tokio::test]
+    async fn http_connections_are_not_limited() {
+        let limits = Limits { connections_per_ip: 1, ..Limits::default() };
+        let server = ArenaServer::new(Settings { limits, ..Settings::default() });
+        let peer: SocketAddr = "[::1]:4000".parse().unwrap();
+        let (_client, io) = tokio::io::duplex(1024);
+        let mut http = Box::pin(accepted(io, Some(peer), Protocol::Http, None, server.clone()));
+        assert!(futures::poll!(&mut http).is_pending());
+        assert!(server.open_from(peer.ip()));
+    }
+
+    #[test]
+    fn full_outbox_closes_the_connection() {
+        let server = ArenaServer::new(Settings::default());
+        let (outbox, mut pushed) = mpsc::channel(0);
+        server.connect("a", 1, outbox);
+        assert!(server.push("a", Announcement { message: "first".into() }));
+        assert!(server.push("a", Announcement { message: "second".into() }));
+        assert_eq!(Ok(Announcement { message: "first".into() }), pushed.try_recv());
+        assert_eq!(Err(TryRecvError::Closed), pushed.try_recv());
+        assert!(server.metrics.render().contains("shotgun_rate_limited_total{limit=\"outbox\"} 1\n"));
+    }
+
+    #[test]
+    fn reload_announces_round_length() {
+        let server = ArenaServer::new(Settings::default());
+        let mut a = connect(&server, "a");
+        server.reload(Settings { friendly_fire: true, ..Settings::default() });
+        assert_eq!(Err(TryRecvError::Empty), a.try_recv());
+        let max_round_length = Duration::from_millis(1500);
+        server.reload(Settings { max_round_length, ..Settings::default() });
+        assert_eq!(Ok(ServerHello { max_round_length, session: None }), a.try_recv());
+        assert_eq!(max_round_length, server.settings().max_round_length);
+    }
+
+    #[tokio::test]
+    async fn closed_connection_leaves_the_lobby() {
+        use tokio::io::AsyncWriteExt;
+        let server = ArenaServer::new(Settings::default());
+        let (mut a, io) = tokio::io::duplex(1024);
+        let served = tokio::spawn(serve_player(lines(io), server.clone(), true));
+        a.write_all(b"Nickname: >alice<>rust\nRequestNewGame\n").await.unwrap();
+        while server.lobby.lock().unwrap().get(&2).is_none_or(|queue| queue.is_empty()) {
+            tokio::task::yield_now().await;
+        }
+        drop(a);
+        served.await.unwrap().unwrap();
+
+        let mut players = Vec::new();
+        for nickname in ["bob", "carol"] {
+            let (client, io) = tokio::io::duplex(1024);
+            tokio::spawn(serve_player(lines(io), server.clone(), true));
+            let mut client = Client::new(client);
+            client.call(ClientHello { nickname: nickname.into(), programming_language: "rust".into(), token: None }).await.unwrap();
+            players.push(tokio::spawn(async move { client.call(RequestNewGame).await.unwrap() }));
+        }
+        for player in players {
+            match player.await.unwrap() {
+                MultiplexedMessage { action: Action::NewGame { player_name_a, player_name_b }, .. } => assert_eq!(("bob", "carol"), (&*player_name_a, &*player_name_b)),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn rejected_handshake_closes() {
+        let server = ArenaServer::new(Settings::default());
+        let (client, io) = tokio::io::duplex(1024);
+        let served = tokio::spawn(serve_player(lines(io), server.clone(), true));
+        let mut client = Client::new(client);
+        let rejected = client.call(Resume { token: "unknown".into() }).await.unwrap();
+        assert_eq!(HandshakeRejected { reason: "unknown or expired session".into() }, rejected);
+        served.await.unwrap().unwrap();
+        assert!(client.call(ClientHello { nickname: "alice".into(), programming_language: "rust".into(), token: None }).await.is_err());
+    }
+
+    /// Plays `Load` and `Shoot` as `player_name_a`, otherwise `Load` until it is hit
+    async fn play_one_game<T>(mut client: Client<T>, nickname: String, started: Arc<tokio::sync::Barrier>, server: Arc<ArenaServer>, games: usize) -> Action
+        where T: AsyncRead + AsyncWrite + Unpin
+    {
+        client.call(ClientHello { nickname: nickname.clone(), programming_language: "rust".into(), token: None }).await.unwrap();
+        let mut line = client.call(RequestNewGame).await.unwrap();
+        let (mut game, mut moves) = match line {
+            MultiplexedMessage { game_id, action: Action::NewGame { ref player_name_a, ref player_name_b } } => {
+                let moves = if *player_name_a == nickname { vec![RoundAction::Load, RoundAction::Shoot] } else { vec![] };
+                (GameState::new(game_id, vec![player_name_a.clone(), player_name_b.clone()]), moves.into_iter())
+            }
+            ref other => panic!("unexpected {:?}", other),
+        };
+        // Nobody moves before every game is running
+        if started.wait().await.is_leader() {
+            assert_eq!(games, server.live_games(|_| Some(())).len());
+        }
+        loop {
+            match line {
+                // The outcome is pushed after the last `RoundResult`
+                MultiplexedMessage { action: outcome @ Action::WinGame, .. } | MultiplexedMessage { action: outcome @ Action::LoseGame, .. } => return outcome,
+                MultiplexedMessage { action: Action::RoundResult { ref a, ref b }, .. } => {
+                    game.follow_duel(a.clone(), b.clone());
+                    // After the last round only the outcome follows
+                    line = match game.is_over() {
+                        true => client.receive().await.unwrap(),
+                        false => client.call(line.answer(Action::PlayerInput(moves.next().unwrap_or(RoundAction::Load)))).await.unwrap(),
+                    };
+                }
+                MultiplexedMessage { .. } => line = client.call(line.answer(Action::PlayerInput(moves.next().unwrap_or(RoundAction::Load)))).await.unwrap(),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn thousands_of_concurrent_games() {
+        const GAMES: usize = 2000;
+        let server = ArenaServer::new(Settings::default());
+        let started = Arc::new(tokio::sync::Barrier::new(2 * GAMES));
+        let players: Vec<_> = (0..2 * GAMES).map(|i| {
+            let (client, io) = tokio::io::duplex(1024);
+            tokio::spawn(serve_player(lines(io), server.clone(), true));
+            tokio::spawn(play_one_game(Client::new(client), format!("bot{}", i), started.clone(), server.clone(), GAMES))
+        }).collect();
+
+        let mut won = 0;
+        for player in players {
+            if player.await.unwrap() == Action::WinGame {
+                won += 1;
+            }
+        }
+        assert_eq!(GAMES, won);
+        assert_eq!(0, server.live_games(|_| Some(())).len());
+        let statistics = server.statistics();
+        assert_eq!(2 * GAMES, statistics.len());
+        assert_eq!(GAMES as u64, statistics.values().map(|s| s.0).sum::<u64>());
+        assert_eq!(GAMES as u64, statistics.values().map(|s| s.1).sum::<u64>());
     }
 }
@@ -0,0 +1,211 @@
+//! Counters and histograms for monitoring, served at `/metrics` in the Prometheus text format
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use shotgun_common::ParseError;
+
+/// Upper bounds in seconds for how long a player takes to answer a round
+const LATENCY_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+/// Upper bounds in seconds for how long a player waits in the lobby
+const QUEUE_BUCKETS: &[f64] = &[0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0];
+
+#[derive(Default)]
+pub struct Counter(AtomicUsize);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// A counter for every value of one label
+pub struct LabeledCounter {
+    label: &'static str,
+    values: Mutex<BTreeMap<String, u64>>,
+}
+
+impl LabeledCounter {
+    pub fn new(label: &'static str) -> LabeledCounter {
+        LabeledCounter { label, values: Mutex::new(BTreeMap::new()) }
+    }
+
+    pub fn inc(&self, value: &str) {
+        *self.values.lock().unwrap().entry(value.to_string()).or_insert(0) += 1;
+    }
+}
+
+pub struct Histogram {
+    buckets: &'static [f64],
+    /// Observations per bucket, the last one is `+Inf`
+    counts: Mutex<(Vec<u64>, f64)>,
+}
+
+impl Histogram {
+    pub fn new(buckets: &'static [f64]) -> Histogram {
+        Histogram { buckets, counts: Mutex::new((vec![0; buckets.len() + 1], 0.0)) }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9;
+        let bucket = self.buckets.iter().position(|&le| seconds <= le).unwrap_or(self.buckets.len());
+        let mut counts = self.counts.lock().unwrap();
+        counts.0[bucket] += 1;
+        counts.1 += seconds;
+    }
+}
+
+pub struct Metrics {
+    pub connections: Counter,
+    pub open_connections: Counter,
+    /// By `result`: succeeded or failed
+    pub handshakes: LabeledCounter,
+    pub games_started: Counter,
+    /// By `outcome`: win, team_win or draw
+    pub games_finished: LabeledCounter,
+    /// By `nickname`
+    pub timeouts: LabeledCounter,
+    pub round_latency: Histogram,
+    /// By `kind`, the variant of `ParseError`
+    pub parse_errors: LabeledCounter,
+    pub queue_wait: Histogram,
+    /// By `limit`, which of the `Limits` refused a request or connection
+    pub rate_limited: LabeledCounter,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            connections: Counter::default(),
+            open_connections: Counter::default(),
+            handshakes: LabeledCounter::new("result"),
+            games_started: Counter::default(),
+            games_finished: LabeledCounter::new("outcome"),
+            timeouts: LabeledCounter::new("nickname"),
+            round_latency: Histogram::new(LATENCY_BUCKETS),
+            parse_errors: LabeledCounter::new("kind"),
+            queue_wait: Histogram::new(QUEUE_BUCKETS),
+            rate_limited: LabeledCounter::new("limit"),
+        }
+    }
+
+    /// Count the error if a line could not be parsed
+    pub fn count_error(&self, e: &io::Error) {
+        if let Some(parse_error) = e.get_ref().and_then(|inner| inner.downcast_ref::<ParseError>()) {
+            self.parse_errors.inc(parse_error.kind());
+        }
+    }
+
+    /// The TLS, WebSocket or line handshake of a new connection failed
+    pub fn handshake_failed(&self, e: &io::Error) {
+        self.handshakes.inc("failed");
+        self.count_error(e);
+    }
+
+    /// Everything in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        counter(&mut out, "shotgun_connections_total", "Accepted connections", self.connections.get());
+        gauge(&mut out, "shotgun_open_connections", "Connections currently open", self.open_connections.get());
+        labeled(&mut out, "shotgun_handshakes_total", "Handshakes by result", &self.handshakes);
+        counter(&mut out, "shotgun_games_started_total", "Games started", self.games_started.get());
+        labeled(&mut out, "shotgun_games_finished_total", "Games finished by outcome", &self.games_finished);
+        labeled(&mut out, "shotgun_timeouts_total", "Rounds a player did not move in time", &self.timeouts);
+        histogram(&mut out, "shotgun_round_latency_seconds", "Time from the start of a round until a move arrives", &self.round_latency);
+        labeled(&mut out, "shotgun_parse_errors_total", "Lines that could not be parsed by ParseError variant", &self.parse_errors);
+        histogram(&mut out, "shotgun_queue_wait_seconds", "Time players wait in the lobby for a game", &self.queue_wait);
+        labeled(&mut out, "shotgun_rate_limited_total", "Requests and connections refused by limit", &self.rate_limited);
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: usize) {
+    header(out, name, help, "counter");
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: usize) {
+    header(out, name, help, "gauge");
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn labeled(out: &mut String, name: &str, help: &str, counter: &LabeledCounter) {
+    header(out, name, help, "counter");
+    for (value, count) in counter.values.lock().unwrap().iter() {
+        let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", name, counter.label, escape(value), count);
+    }
+}
+
+fn histogram(out: &mut String, name: &str, help: &str, histogram: &Histogram) {
+    header(out, name, help, "histogram");
+    let counts = histogram.counts.lock().unwrap();
+    let mut cumulative = 0;
+    for (i, count) in counts.0.iter().enumerate() {
+        cumulative += count;
+        let le = histogram.buckets.get(i).map(|le| le.to_string()).unwrap_or_else(|| "+Inf".to_string());
+        let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, cumulative);
+    }
+    let _ = writeln!(out, "{}_sum {}", name, counts.1);
+    let _ = writeln!(out, "{}_count {}", name, cumulative);
+}
+
+/// Label values may contain anything, nicknames for example
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_counters() {
+        let metrics = Metrics::new();
+        metrics.connections.inc();
+        metrics.handshakes.inc("succeeded");
+        metrics.timeouts.inc("say \"hi\"");
+        let text = metrics.render();
+        assert!(text.contains("# TYPE shotgun_connections_total counter\nshotgun_connections_total 1\n"), "{}", text);
+        assert!(text.contains("shotgun_handshakes_total{result=\"succeeded\"} 1\n"), "{}", text);
+        assert!(text.contains("shotgun_timeouts_total{nickname=\"say \\\"hi\\\"\"} 1\n"), "{}", text);
+    }
+
+    #[test]
+    fn render_histogram() {
+        let metrics = Metrics::new();
+        metrics.round_latency.observe(Duration::from_millis(20));
+        metrics.round_latency.observe(Duration::from_secs(60));
+        let text = metrics.render();
+        assert!(text.contains("shotgun_round_latency_seconds_bucket{le=\"0.01\"} 0\n"), "{}", text);
+        assert!(text.contains("shotgun_round_latency_seconds_bucket{le=\"0.025\"} 1\n"), "{}", text);
+        assert!(text.contains("shotgun_round_latency_seconds_bucket{le=\"+Inf\"} 2\n"), "{}", text);
+        assert!(text.contains("shotgun_round_latency_seconds_sum 60.02\n"), "{}", text);
+        assert!(text.contains("shotgun_round_latency_seconds_count 2\n"), "{}", text);
+    }
+
+    #[test]
+    fn count_parse_errors() {
+        let metrics = Metrics::new();
+        metrics.count_error(&io::Error::other(ParseError::ExpectedValue));
+        metrics.count_error(&io::Error::other("something else"));
+        let text = metrics.render();
+        assert!(text.contains("# TYPE shotgun_parse_errors_total counter\nshotgun_parse_errors_total{kind=\"ExpectedValue\"} 1\n# HELP"), "{}", text);
+    }
+}
@@ -0,0 +1,101 @@
+//! Best-of-N match series between two players
+
+use shotgun_common::*;
+use shotgun_common::Action::*;
+use crate::Waiting;
+
+pub struct MatchSeries {
+    pub match_id: u64,
+    /// How many games are played at most
+    pub games: u64,
+    pub players: [String; 2],
+    /// Games won by each player, draws count for nobody
+    pub score: [u64; 2],
+    pub played: u64,
+    /// A game of this series is running, the next one starts after it
+    pub running: bool,
+    /// Players who asked for the next game with `RequestNewGame`
+    pub waiting: Vec<(String, Waiting)>,
+}
+
+impl MatchSeries {
+    pub fn new(match_id: u64, games: u64, players: [String; 2]) -> MatchSeries {
+        MatchSeries {
+            match_id,
+            games,
+            players,
+            score: [0, 0],
+            played: 0,
+            running: false,
+            waiting: Vec::new(),
+        }
+    }
+
+    /// The players of the next game, alternating who is `player_name_a`
+    pub fn next_lineup(&self) -> Vec<String> {
+        let (a, b) = if self.played.is_multiple_of(2) { (0, 1) } else { (1, 0) };
+        vec![self.players[a].clone(), self.players[b].clone()]
+    }
+
+    /// Count a finished game of this series
+    pub fn record(&mut self, game: &GameState) {
+        self.played += 1;
+        self.running = false;
+        for (i, player) in self.players.iter().enumerate() {
+            if game.has_won(player) {
+                self.score[i] += 1;
+            }
+        }
+    }
+
+    /// Over after all games or as soon as one player won the majority
+    pub fn is_over(&self) -> bool {
+        self.played >= self.games || self.score.iter().any(|&s| s * 2 > self.games)
+    }
+
+    /// The final score from the perspective of `nickname`
+    pub fn result_for(&self, nickname: &str) -> Action {
+        let (me, other) = if self.players[0] == nickname { (0, 1) } else { (1, 0) };
+        MatchResult {
+            won: self.score[me],
+            lost: self.score[other],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shotgun_common::RoundAction::*;
+
+    fn won_by(winner: &str, lineup: Vec<String>) -> GameState {
+        let loser = lineup.iter().find(|p| *p != winner).unwrap().clone();
+        let mv = |player: &str, action: RoundAction| PlayerMove { player: player.into(), action, target: None };
+        let mut game = GameState::new(1, lineup);
+        game.resolve_round(vec![mv(winner, Load), mv(&loser, Load)]);
+        game.resolve_round(vec![mv(winner, Shoot), mv(&loser, Load)]);
+        game
+    }
+
+    #[test]
+    fn lineup_alternates() {
+        let mut series = MatchSeries::new(1, 3, ["a".into(), "b".into()]);
+        assert_eq!(vec!["a".to_string(), "b".to_string()], series.next_lineup());
+        let game = won_by("a", series.next_lineup());
+        series.record(&game);
+        assert_eq!(vec!["b".to_string(), "a".to_string()], series.next_lineup());
+    }
+
+    #[test]
+    fn majority_ends_series() {
+        let mut series = MatchSeries::new(1, 3, ["a".into(), "b".into()]);
+        for _ in 0..2 {
+            assert!(!series.is_over());
+            let game = won_by("b", series.next_lineup());
+            series.record(&game);
+        }
+        assert!(series.is_over());
+        assert_eq!(MatchResult { won: 2, lost: 0 }, series.result_for("b"));
+        assert_eq!(MatchResult { won: 0, lost: 2 }, series.result_for("a"));
+    }
+}
@@ -0,0 +1,41 @@
+//! A map split into shards with a lock each, so requests on different shards never wait for each other
+
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+
+/// Keys are spread over the shards by their remainder
+pub struct Shards<V> {
+    shards: Vec<Mutex<HashMap<u64, V>>>,
+}
+
+impl<V> Shards<V> {
+    pub fn new(count: usize) -> Shards<V> {
+        Shards { shards: (0..count.max(1)).map(|_| Mutex::new(HashMap::new())).collect() }
+    }
+
+    /// Lock the shard `key` belongs to
+    pub fn lock(&self, key: u64) -> MutexGuard<'_, HashMap<u64, V>> {
+        self.shards[(key % self.shards.len() as u64) as usize].lock().unwrap()
+    }
+
+    /// Lock one shard after the other, each is unlocked before the next one
+    pub fn each(&self) -> impl Iterator<Item = MutexGuard<'_, HashMap<u64, V>>> {
+        self.shards.iter().map(|shard| shard.lock().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_are_spread() {
+        let shards = Shards::new(4);
+        for key in 0..10 {
+            shards.lock(key).insert(key, key * 2);
+        }
+        assert_eq!(Some(&14), shards.lock(7).get(&7));
+        assert!(shards.each().all(|shard| shard.len() >= 2));
+        assert_eq!(10, shards.each().map(|shard| shard.len()).sum::<usize>());
+    }
+}
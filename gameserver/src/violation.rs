@@ -0,0 +1,85 @@
+//! What a client may not do, each with a well-defined message
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use shotgun_common::*;
+
+/// A request that breaks the protocol
+#[derive(Debug,Clone,PartialEq)]
+pub enum Violation {
+    /// A request before the handshake was accepted
+    NoHandshake,
+    /// A second `ClientHello` or `Resume` on the same connection
+    RepeatedHandshake,
+    /// A line only the server sends, like `WinGame` or `NewGame`
+    ServerMessage(ParsedLine),
+    /// No running game with this id
+    UnknownGame(u64),
+    /// The game runs without this player
+    NotInGame(u64),
+    /// The player is out of this game already
+    Eliminated(u64),
+    /// Only `Load`, `Duck` and `Shoot` are moves
+    InvalidMove(RoundAction),
+    /// The target is not an opponent in this game
+    InvalidTarget(String),
+    /// The player already moved in this round
+    DuplicateMove(u64),
+    /// No open challenge for this player with this id
+    UnknownChallenge(u64),
+    /// A match series of zero games
+    NoGames,
+    /// A free-for-all arena for fewer than two players
+    TooFewPlayers(u64),
+}
+
+use self::Violation::*;
+
+impl Violation {
+    /// Closes the connection, otherwise only the move or answer is refused
+    pub fn is_fatal(&self) -> bool {
+        matches!(*self, NoHandshake | RepeatedHandshake | ServerMessage(_))
+    }
+
+    /// How the `Error` names it on the wire
+    pub fn code(&self) -> ErrorCode {
+        match *self {
+            NoHandshake | RepeatedHandshake => ErrorCode::InvalidHandshake,
+            ServerMessage(_) => ErrorCode::UnexpectedMessage,
+            UnknownGame(_) | NotInGame(_) | UnknownChallenge(_) => ErrorCode::UnknownGame,
+            Eliminated(_) => ErrorCode::Eliminated,
+            InvalidMove(_) | InvalidTarget(_) => ErrorCode::InvalidMove,
+            DuplicateMove(_) => ErrorCode::DuplicateMove,
+            NoGames | TooFewPlayers(_) => ErrorCode::InvalidRequest,
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NoHandshake => write!(f, "handshake required"),
+            RepeatedHandshake => write!(f, "handshake already done"),
+            ServerMessage(ref line) => write!(f, "only the server sends {:?}", line),
+            UnknownGame(game_id) => write!(f, "no running game {}", game_id),
+            NotInGame(game_id) => write!(f, "not a player of game {}", game_id),
+            Eliminated(game_id) => write!(f, "already out of game {}", game_id),
+            InvalidMove(ref action) => write!(f, "{:?} is not a move", action),
+            InvalidTarget(ref target) => write!(f, "{:?} is no opponent", target),
+            DuplicateMove(game_id) => write!(f, "already moved this round of game {}", game_id),
+            UnknownChallenge(game_id) => write!(f, "no open challenge {}", game_id),
+            NoGames => write!(f, "a match needs at least one game"),
+            TooFewPlayers(players) => write!(f, "an arena needs at least two players, not {}", players),
+        }
+    }
+}
+
+impl Error for Violation {}
+
+impl From<Violation> for io::Error {
+    fn from(violation: Violation) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, violation)
+    }
+}
@@ -0,0 +1,193 @@
+'use strict';
+
+// Everything comes from the JSON API of the gameserver serving this page
+const POLL_MS = 1000
+  , REPLAYS_POLL_MS = 5000
+  , STEP_MS = 700;
+
+// Rounds already shown per live game id
+var shown_rounds = {};
+var replay = null;
+
+function get(path) {
+  return fetch(path).then((response) => {
+    if (!response.ok) {
+      throw new Error(path + ': ' + response.status);
+    }
+    return response.json();
+  });
+}
+
+function element(tag, class_name, text) {
+  const el = document.createElement(tag);
+  if (class_name) {
+    el.className = class_name;
+  }
+  if (text !== undefined) {
+    el.textContent = text;
+  }
+  return el;
+}
+
+function set_status(text, error) {
+  const status = document.getElementById('status');
+  status.textContent = text;
+  status.classList.toggle('error', !!error);
+}
+
+function render_leaderboard(players) {
+  const body = document.querySelector('#leaderboard tbody');
+  body.textContent = '';
+  players.forEach((p, i) => {
+    const row = element('tr');
+    [i + 1, p.nickname, p.won, p.lost].forEach((value) => row.appendChild(element('td', null, value)));
+    body.appendChild(row);
+  });
+}
+
+// Draw the players of a game after a round, with the moves of that round if there are any
+function render_arena(container, title, players, moves, winner) {
+  container.textContent = '';
+  container.appendChild(element('h3', null, title));
+  players.forEach((p) => {
+    const row = element('div', 'player');
+    row.classList.toggle('dead', !p.alive);
+    row.classList.toggle('winner', p.nickname === winner);
+    row.appendChild(element('span', 'name', p.nickname + (p.team !== null ? ' [' + p.team + ']' : '')));
+    row.appendChild(element('span', 'ammo', '•'.repeat(p.ammo_bag)));
+    const mv = (moves || []).find((m) => m.player === p.nickname);
+    if (mv) {
+      const text = mv.action + (mv.target ? ' → ' + mv.target : '');
+      row.appendChild(element('span', 'action action-' + mv.action, text));
+    }
+    container.appendChild(row);
+  });
+}
+
+function game_title(game) {
+  return '#' + game.game_id + (game.match_id !== null ? ' (match ' + game.match_id + ')' : '');
+}
+
+// Animate every round of a live game that was not shown yet
+function follow_game(container, game) {
+  const seen = shown_rounds[game.game_id] || 0;
+  if (seen === 0 && game.rounds === 0) {
+    render_arena(container, game_title(game), game.players, [], null);
+    return;
+  }
+  if (seen >= game.rounds) {
+    return;
+  }
+  shown_rounds[game.game_id] = game.rounds;
+  get('/games/' + game.game_id + '/replay').then((data) => {
+    data.rounds.slice(seen).forEach((round, i) => {
+      setTimeout(() => {
+        const title = game_title(game) + ' round ' + (seen + i + 1);
+        render_arena(container, title, round.players, round.moves, null);
+      }, i * STEP_MS);
+    });
+  });
+}
+
+function poll_live() {
+  Promise.all([get('/players'), get('/games/live')]).then(([players, games]) => {
+    render_leaderboard(players);
+
+    const arenas = document.querySelector('#live .arenas');
+    const live_ids = games.map((g) => String(g.game_id));
+    Array.from(arenas.children).forEach((el) => {
+      if (live_ids.indexOf(el.dataset.gameId) === -1) {
+        delete shown_rounds[el.dataset.gameId];
+        arenas.removeChild(el);
+      }
+    });
+    games.forEach((game) => {
+      var container = arenas.querySelector('[data-game-id="' + game.game_id + '"]');
+      if (!container) {
+        container = element('div', 'arena');
+        container.dataset.gameId = game.game_id;
+        arenas.appendChild(container);
+      }
+      follow_game(container, game);
+    });
+    set_status(games.length + ' live games');
+  }).catch((err) => set_status(err.message, true));
+}
+
+function poll_finished() {
+  get('/games/finished').then((games) => {
+    const list = document.querySelector('#replays .finished');
+    list.textContent = '';
+    games.forEach((game) => {
+      const names = game.players.map((p) => p.nickname).join(' vs ');
+      const item = element('li', null, game_title(game) + ': ' + names + ' — ' + (game.winner || 'draw'));
+      item.classList.toggle('selected', replay !== null && replay.game.game_id === game.game_id);
+      item.addEventListener('click', () => open_replay(game));
+      list.appendChild(item);
+    });
+  }).catch((err) => set_status(err.message, true));
+}
+
+function open_replay(game) {
+  get('/games/' + game.game_id + '/replay').then((data) => {
+    stop_replay();
+    replay = { game: game, rounds: data.rounds, round: 0, timer: null };
+    document.querySelector('#replays .viewer').hidden = false;
+    show_round();
+    poll_finished();
+  });
+}
+
+function show_round() {
+  const viewer = document.querySelector('#replays .viewer');
+  const round = replay.rounds[replay.round - 1];
+  const players = round ? round.players : replay.game.players.map((p) => ({
+    nickname: p.nickname, ammo_bag: 0, alive: true, team: p.team,
+  }));
+  const winner = replay.round === replay.rounds.length ? replay.game.winner : null;
+  render_arena(viewer.querySelector('.arena'), game_title(replay.game), players, round ? round.moves : [], winner);
+  viewer.querySelector('.round').textContent = 'round ' + replay.round + ' / ' + replay.rounds.length;
+}
+
+function stop_replay() {
+  if (replay && replay.timer) {
+    clearInterval(replay.timer);
+    replay.timer = null;
+  }
+}
+
+function step(kind) {
+  if (!replay) {
+    return;
+  }
+  stop_replay();
+  if (kind === 'first') {
+    replay.round = 0;
+  } else if (kind === 'prev') {
+    replay.round = Math.max(0, replay.round - 1);
+  } else if (kind === 'next') {
+    replay.round = Math.min(replay.rounds.length, replay.round + 1);
+  } else if (kind === 'play') {
+    if (replay.round === replay.rounds.length) {
+      replay.round = 0;
+    }
+    replay.timer = setInterval(() => {
+      if (replay.round >= replay.rounds.length) {
+        stop_replay();
+        return;
+      }
+      replay.round += 1;
+      show_round();
+    }, STEP_MS);
+  }
+  show_round();
+}
+
+document.querySelectorAll('#replays .controls button').forEach((button) => {
+  button.addEventListener('click', () => step(button.dataset.step));
+});
+
+poll_live();
+poll_finished();
+setInterval(poll_live, POLL_MS);
+setInterval(poll_finished, REPLAYS_POLL_MS);
@@ -11,41 +11,65 @@ const LOAD = 'Load'
   , ROUND_RESULT = 'RoundResult'
   , REQUEST_NEW_GAME = 'RequestNewGame'
   , CLIENT_HELLO = 'Nickname: >dns2utf8<>javascript'
-  , SERVER_HELLO = 'Shotgun Arena Server v0 :: max round length[ms]: 200'
+  , SERVER_HELLO = 'Shotgun Arena Server v0 :: max round length[ms]: '
+  , SESSION = ' :: session: '
+
+// Needed to `Resume` the games after a dropped connection
+var session = null;
 
 
 const net = require('net');
+const readline = require('readline');
+
+var client = null;
+var rl = null;
 
-const client = net.connect({host: '::1', port: 6000}, () => {
-  // 'connect' listener
-  console.log('connected to server!');
-  client.write(CLIENT_HELLO + '\n');
-});
-
-var buf = '';
-client.on('data', (data) => {
-  buf += data.toString();
-
-  var next_nl = -1;
-  while ((next_nl = buf.indexOf('\n')) !== -1) {
-    var msg = buf.substr(0, next_nl);
-    buf = buf.substr(next_nl + 1);
-    proto_handler(msg);
+// The round length and the session token of a `ServerHello`, null for any other line
+function server_hello(msg) {
+  if (!msg.startsWith(SERVER_HELLO)) {
+    return null;
   }
-});
-client.on('end', () => {
-  console.log('disconnected from server; buf: '+buf);
-  rl.close();
-});
-client.on('error', (err) => {
-  console.log('ERROR: ' + err);
-  rl.close();
-});
+  const parts = msg.substr(SERVER_HELLO.length).split(SESSION);
+  return {
+    max_round_length: parseInt(parts[0]),
+    session: parts.length > 1 ? parts[1] : null,
+  };
+}
+
+function connect() {
+  client = net.connect({host: '::1', port: 6000}, () => {
+    // 'connect' listener
+    console.log('connected to server!');
+    client.write(CLIENT_HELLO + '\n');
+  });
+
+  var buf = '';
+  client.on('data', (data) => {
+    buf += data.toString();
+
+    var next_nl = -1;
+    while ((next_nl = buf.indexOf('\n')) !== -1) {
+      var msg = buf.substr(0, next_nl);
+      buf = buf.substr(next_nl + 1);
+      proto_handler(msg);
+    }
+  });
+  client.on('end', () => {
+    console.log('disconnected from server; buf: '+buf);
+    rl.close();
+  });
+  client.on('error', (err) => {
+    console.log('ERROR: ' + err);
+    rl.close();
+  });
+}
 
 function proto_handler(msg) {
   //console.log('> '+msg);
 
-  if (msg === SERVER_HELLO) {
+  const hello = server_hello(msg);
+  if (hello) {
+    session = hello.session;
     for (var i = 0; i < max_games; ++i) {
       client.write('RequestNewGame\n');
     }
@@ -89,13 +113,17 @@ function respond(game_id, command) {
 
 
 ///////////////////////////////////////////////////////////////////////////////////
-const readline = require('readline');
+module.exports = { server_hello };
 
-const rl = readline.createInterface({
-  input: process.stdin,
-  output: process.stdout
-});
-rl.on('line', (input) => {
-  //console.log(`Received: ${input}`);
-  console.log(game_states);
-});
+if (require.main === module) {
+  rl = readline.createInterface({
+    input: process.stdin,
+    output: process.stdout
+  });
+  rl.on('line', (input) => {
+    //console.log(`Received: ${input}`);
+    console.log(game_states);
+    console.log('session: ' + session);
+  });
+  connect();
+}
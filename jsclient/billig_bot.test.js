@@ -0,0 +1,19 @@
+'use strict';
+
+// Run with `node --test jsclient`
+const test = require('node:test');
+const assert = require('node:assert');
+const { server_hello } = require('./billig_bot.js');
+
+test('server hello with session', () => {
+  const banner = 'Shotgun Arena Server v0 :: max round length[ms]: 1500 :: session: 9f86d081884c7d659a2feaa0c55ad015';
+  assert.deepStrictEqual(server_hello(banner), { max_round_length: 1500, session: '9f86d081884c7d659a2feaa0c55ad015' });
+});
+
+test('server hello without session', () => {
+  assert.deepStrictEqual(server_hello('Shotgun Arena Server v0 :: max round length[ms]: 200'), { max_round_length: 200, session: null });
+});
+
+test('other lines are no server hello', () => {
+  assert.strictEqual(server_hello('3:NewGame { player_name_a: "a", player_name_b: "b" }'), null);
+});
@@ -0,0 +1,131 @@
+//! Commands of the admin channel, one per line in their `Debug` format
+//!
+//! The gameserver answers every command with one line of JSON, `{"error":"…"}` if it failed.
+//!
+//! ```
+//! # use shotgun_common::admin::Command;
+//! let command: Command = "Kick { nickname: \"coward_bot\" }".parse().unwrap();
+//! assert_eq!("Kick { nickname: \"coward_bot\" }", format!("{:?}", command));
+//! ```
+
+use crate::{invalid_action, split_quoted, split_quoted_list, strip_frame, to_parse_error, ParseError};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Required first, the server closes the connection on a wrong token
+    Authenticate { token: String },
+    /// Every connected player with its connections and running games
+    ListConnections,
+    /// Every running game
+    ListArenas,
+    /// Close every connection of the player, its running games are forfeit
+    Kick { nickname: String },
+    /// End a running game with `Error { code: Aborted, … }` and `ErrorEnd`, it does not count
+    Abort { game_id: u64, message: String },
+    /// Forget all wins and losses
+    ResetStatistics,
+    /// Overwrite the wins and losses of one player, zero for both forgets them
+    SetStatistics { nickname: String, won: u64, lost: u64 },
+    /// Everybody plays a match series of `games` against everybody else, handed out on `RequestNewGame`
+    StartTournament { games: u64, players: Vec<String> },
+    /// Push an `Announcement` to every connection
+    Broadcast { message: String },
+    /// Read the configuration again, like on SIGHUP
+    Reload,
+}
+use Command::*;
+
+impl std::str::FromStr for Command {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ListConnections" => return Ok(ListConnections),
+            "ListArenas" => return Ok(ListArenas),
+            "ResetStatistics" => return Ok(ResetStatistics),
+            "Reload" => return Ok(Reload),
+            _ => {},
+        }
+
+        if let Ok(inner) = strip_frame(s, "Authenticate { token: ", " }") {
+            return Ok(Authenticate { token: quoted(s, inner)? })
+        }
+        if let Ok(inner) = strip_frame(s, "Kick { nickname: ", " }") {
+            return Ok(Kick { nickname: quoted(s, inner)? })
+        }
+        if let Ok(inner) = strip_frame(s, "Broadcast { message: ", " }") {
+            return Ok(Broadcast { message: quoted(s, inner)? })
+        }
+
+        if let Ok(inner) = strip_frame(s, "Abort { game_id: ", " }") {
+            let mut parts = inner.splitn(2, ", message: ");
+            return Ok(Abort {
+                game_id: to_parse_error( parts.next() )?.parse().map_err(|_| invalid_action(s))?,
+                message: quoted(s, to_parse_error( parts.next() )?)?,
+            })
+        }
+
+        if let Ok(inner) = strip_frame(s, "SetStatistics { nickname: ", " }") {
+            let (nickname, rest) = split_quoted(inner)?;
+            let rest = rest.strip_prefix(", won: ").ok_or_else(|| invalid_action(s))?;
+            let mut parts = rest.splitn(2, ", lost: ");
+            return Ok(SetStatistics {
+                nickname,
+                won: to_parse_error( parts.next() )?.parse().map_err(|_| invalid_action(s))?,
+                lost: to_parse_error( parts.next() )?.parse().map_err(|_| invalid_action(s))?,
+            })
+        }
+
+        if let Ok(inner) = strip_frame(s, "StartTournament { games: ", " }") {
+            let mut parts = inner.splitn(2, ", players: ");
+            let games = to_parse_error( parts.next() )?.parse().map_err(|_| invalid_action(s))?;
+            let (players, rest) = split_quoted_list(to_parse_error( parts.next() )?)?;
+            if !rest.is_empty() {
+                return Err(invalid_action(s))
+            }
+            return Ok(StartTournament { games, players })
+        }
+
+        Err(ParseError::InvalidAction(format!("invalid Command: {:?}", s)))
+    }
+}
+
+/// The whole of `inner` is one quoted string
+fn quoted(text: &str, inner: &str) -> Result<String, ParseError> {
+    let (value, rest) = split_quoted(inner)?;
+    if !rest.is_empty() {
+        return Err(invalid_action(text))
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_round_trip() {
+        let commands = vec![
+            Authenticate { token: "secret".into() },
+            ListConnections,
+            ListArenas,
+            Kick { nickname: "b c".into() },
+            Abort { game_id: 7, message: "cheating".into() },
+            ResetStatistics,
+            SetStatistics { nickname: "a".into(), won: 3, lost: 1 },
+            StartTournament { games: 3, players: vec!["a".into(), "b".into(), "c".into()] },
+            Broadcast { message: "restart at noon, sorry".into() },
+            Reload,
+        ];
+        for command in commands {
+            assert_eq!(Ok(command.clone()), format!("{:?}", command).parse());
+        }
+    }
+
+    #[test]
+    fn parse_invalid_command() {
+        assert_eq!(Err(ParseError::InvalidAction("invalid Command: \"Shutdown\"".into())), "Shutdown".parse::<Command>());
+        assert!("Abort { game_id: x, message: \"\" }".parse::<Command>().is_err());
+        assert!("Kick { nickname: alice }".parse::<Command>().is_err());
+        assert!("StartTournament { games: 3, players: [\"a\" }".parse::<Command>().is_err());
+    }
+}
@@ -0,0 +1,151 @@
+//! Registry of nicknames protected by a token
+//!
+//! The file has one line per nickname: the hex encoded SHA-256 of `nickname:token`, a space and the nickname.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use sha2::{Digest, Sha256};
+
+#[derive(Debug,PartialEq)]
+pub enum AuthError {
+    /// The nickname is registered but no token was sent
+    MissingToken,
+    /// The token does not match the registered one
+    WrongToken,
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            AuthError::MissingToken => write!(f, "nickname is registered, a token is required"),
+            AuthError::WrongToken => write!(f, "invalid token for this nickname"),
+        }
+    }
+}
+
+#[derive(Debug,Default)]
+pub struct Registry {
+    /// Hashed credential by nickname
+    credentials: HashMap<String, String>,
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry::default()
+    }
+
+    /// A missing file is an empty registry
+    pub fn load(path: &Path) -> io::Result<Registry> {
+        let mut content = String::new();
+        match File::open(path) {
+            Ok(mut file) => { file.read_to_string(&mut content)?; },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => return Err(e),
+        }
+
+        let mut registry = Registry::new();
+        for line in content.lines().filter(|l| !l.is_empty()) {
+            let mut parts = line.splitn(2, ' ');
+            let hash = parts.next().unwrap();
+            let nickname = parts.next()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("invalid registry line: {:?}", line)))?;
+            registry.credentials.insert(nickname.into(), hash.into());
+        }
+        Ok(registry)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut nicknames: Vec<&String> = self.credentials.keys().collect();
+        nicknames.sort();
+        let mut file = File::create(path)?;
+        for nickname in nicknames {
+            writeln!(file, "{} {}", self.credentials[nickname], nickname)?;
+        }
+        Ok(())
+    }
+
+    pub fn register(&mut self, nickname: &str, token: &str) {
+        self.credentials.insert(nickname.into(), hash(nickname, token));
+    }
+
+    /// Returns false if the nickname was not registered
+    pub fn unregister(&mut self, nickname: &str) -> bool {
+        self.credentials.remove(nickname).is_some()
+    }
+
+    pub fn is_registered(&self, nickname: &str) -> bool {
+        self.credentials.contains_key(nickname)
+    }
+
+    /// Everybody may use a nickname that is not registered
+    pub fn check(&self, nickname: &str, token: Option<&str>) -> Result<(), AuthError> {
+        match (self.credentials.get(nickname), token) {
+            (None, _) => Ok(()),
+            (Some(_), None) => Err(AuthError::MissingToken),
+            (Some(expected), Some(token)) if *expected == hash(nickname, token) => Ok(()),
+            (Some(_), Some(_)) => Err(AuthError::WrongToken),
+        }
+    }
+}
+
+/// A fresh token of 128 random bits from the operating system, 32 hex digits
+pub fn session_token() -> String {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes).expect("no randomness from the operating system");
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hash(nickname: &str, token: &str) -> String {
+    format!("{:x}", Sha256::digest(format!("{}:{}", nickname, token).as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unregistered_nickname_is_free() {
+        let registry = Registry::new();
+        assert_eq!(Ok(()), registry.check("me", None));
+        assert_eq!(Ok(()), registry.check("me", Some("whatever")));
+    }
+
+    #[test]
+    fn registered_nickname_needs_token() {
+        let mut registry = Registry::new();
+        registry.register("me", "secret");
+        assert_eq!(Err(AuthError::MissingToken), registry.check("me", None));
+        assert_eq!(Err(AuthError::WrongToken), registry.check("me", Some("guess")));
+        assert_eq!(Ok(()), registry.check("me", Some("secret")));
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = std::env::temp_dir().join(format!("shotgun_registry_{}.txt", std::process::id()));
+        let mut registry = Registry::new();
+        registry.register("me", "secret");
+        registry.register("you", "other secret");
+        registry.save(&path).unwrap();
+
+        let loaded = Registry::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(Ok(()), loaded.check("you", Some("other secret")));
+        assert_eq!(Err(AuthError::WrongToken), loaded.check("me", Some("other secret")));
+    }
+
+    #[test]
+    fn session_tokens_differ() {
+        let token = session_token();
+        assert_eq!(32, token.len());
+        assert_ne!(token, session_token());
+    }
+
+    #[test]
+    fn missing_file_is_empty() {
+        let registry = Registry::load(Path::new("/nonexistent/shotgun_registry.txt")).unwrap();
+        assert!(!registry.is_registered("me"));
+    }
+}
@@ -0,0 +1,172 @@
+//! Just enough HTTP/1.1 to upgrade to WebSocket and to answer simple GET requests
+
+use std::io;
+use std::str;
+use bytes::BytesMut;
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+/// Requests with a longer head are refused
+const MAX_HEAD: usize = 8 * 1024;
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Request {
+    /// Header names are case-insensitive
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|&(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: vec![],
+            body: vec![],
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn body(self, content_type: &str, body: Vec<u8>) -> Response {
+        let mut response = self.header("Content-Type", content_type);
+        response.body = body;
+        response
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            101 => "Switching Protocols",
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            _ => "Unknown",
+        }
+    }
+}
+
+pub struct HttpCodec;
+
+impl Decoder for HttpCodec {
+    type Item = Request;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Request>> {
+        let end = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(end) => end,
+            None if buf.len() > MAX_HEAD => return Err(invalid("request head too long")),
+            None => return Ok(None),
+        };
+        let head = buf.split_to(end + 4);
+        let head = str::from_utf8(&head[..end]).map_err(|_| invalid("invalid UTF-8"))?;
+
+        let mut lines = head.split("\r\n");
+        let mut request_line = lines.next().unwrap_or("").split(' ');
+        let (method, path) = match (request_line.next(), request_line.next(), request_line.next()) {
+            (Some(method), Some(path), Some(version)) if version.starts_with("HTTP/1.") => (method, path),
+            _ => return Err(invalid("invalid request line")),
+        };
+        let mut headers = vec![];
+        for line in lines {
+            let mut parts = line.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) => headers.push((name.trim().into(), value.trim().into())),
+                _ => return Err(invalid("invalid header")),
+            }
+        }
+
+        Ok(Some(Request {
+            method: method.into(),
+            path: path.into(),
+            headers,
+        }))
+    }
+}
+
+impl Encoder<Response> for HttpCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, response: Response, buf: &mut BytesMut) -> io::Result<()> {
+        buf.extend_from_slice(format!("HTTP/1.1 {} {}\r\n", response.status, response.reason()).as_bytes());
+        for (name, value) in &response.headers {
+            buf.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+        if response.status != 101 {
+            buf.extend_from_slice(format!("Content-Length: {}\r\n", response.body.len()).as_bytes());
+        }
+        buf.extend_from_slice(b"\r\n");
+        buf.extend_from_slice(&response.body);
+        Ok(())
+    }
+}
+
+/// One response per request, connections are kept alive
+pub async fn serve<T, F>(io: T, mut handle: F) -> io::Result<()>
+    where T: AsyncRead + AsyncWrite + Unpin,
+          F: FnMut(Request) -> Response,
+{
+    let mut http = Framed::new(io, HttpCodec);
+    while let Some(request) = http.next().await {
+        http.send(handle(request?)).await?;
+    }
+    Ok(())
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_request() {
+        let mut buf = BytesMut::from(&b"GET /chat HTTP/1.1\r\nHost: localhost\r\nupgrade: websocket\r\n\r\nrest"[..]);
+        let request = HttpCodec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!("GET", request.method);
+        assert_eq!("/chat", request.path);
+        assert_eq!(Some("websocket"), request.header("Upgrade"));
+        assert_eq!(None, request.header("Origin"));
+        assert_eq!(&b"rest"[..], &buf[..]);
+    }
+
+    #[test]
+    fn decode_partial_request() {
+        let mut buf = BytesMut::from(&b"GET / HTTP/1.1\r\nHost: localhost\r\n"[..]);
+        assert_eq!(None, HttpCodec.decode(&mut buf).unwrap());
+    }
+
+    #[test]
+    fn decode_garbage() {
+        let mut buf = BytesMut::from(&b"Nickname: >me<>rust\r\n\r\n"[..]);
+        assert!(HttpCodec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn encode_response() {
+        let mut buf = BytesMut::new();
+        HttpCodec.encode(Response::new(200).body("application/json", b"[]".to_vec()), &mut buf).unwrap();
+        assert_eq!(&b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 2\r\n\r\n[]"[..], &buf[..]);
+    }
+}
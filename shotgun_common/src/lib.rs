@@ -1,12 +1,27 @@
 extern crate bytes;
 extern crate futures;
-extern crate tokio_io;
-extern crate tokio_proto;
-//extern crate tokio_service;
+extern crate tokio;
+extern crate tokio_util;
+extern crate tokio_rustls;
+extern crate sha1;
+extern crate sha2;
+extern crate rustls;
+extern crate webpki_roots;
+#[macro_use] extern crate tracing;
+extern crate tracing_subscriber;
 
+pub mod admin;
+pub mod auth;
+pub mod http;
+pub mod logging;
 pub mod networking;
+pub mod rules;
+pub mod strategy;
+pub mod tls;
+pub mod websocket;
 
 pub use std::time::Duration;
+pub use rules::{GameState, PlayerMove, PlayerState};
 
 /// Parse like this:
 ///
@@ -14,10 +29,25 @@ pub use std::time::Duration;
 /// # use shotgun_common::Action;
 /// let action: Action = "WinGame".parse().unwrap();
 /// ```
-#[derive(Debug,PartialEq)]
+#[derive(Debug,Clone,PartialEq)]
 pub enum Action {
     /// Starts a new game in this game_id with the opponent
     NewGame { player_name_a: String, player_name_b: String },
+    /// Starts a new free-for-all game in this game_id with all the listed players
+    NewArena { players: Vec<String> },
+    /// Starts a new team game in this game_id, `team_a` plays against `team_b`
+    NewTeamGame { team_a: Vec<String>, team_b: Vec<String> },
+    /// Starts a series of at most `games` games against the same opponent in this match id
+    NewMatch { games: u64 },
+    /// Ends the match series with the final score
+    MatchResult { won: u64, lost: u64 },
+
+    /// Someone wants to play against you, answer with `ChallengeAccepted` or `ChallengeDeclined`
+    ChallengedBy { nickname: String },
+    /// The game starts with `NewGame` in the same game_id
+    ChallengeAccepted,
+    /// No game this time
+    ChallengeDeclined,
 
     /// Ends round and game
     WinGame,
@@ -25,21 +55,61 @@ pub enum Action {
     LoseGame,
 
     PlayerInput(RoundAction),
+    /// Input in a free-for-all game, `target` is the nickname of the player to shoot at
+    TargetedInput { action: RoundAction, target: String },
     /// Ends round
     RoundResult { a: RoundAction, b: RoundAction },
+    /// Ends round in a free-for-all game, lists the move of every surviving player
+    ArenaResult { moves: Vec<PlayerMove> },
 
     /// Some error happend and this game is over
     ErrorEnd,
+    /// The request on this game_id was refused, or why the game ends with the following `ErrorEnd`
+    Error { code: ErrorCode, message: String },
 }
 use Action::*;
 
+/// Why the server refused a request, ended a game or closes the connection
+///
+/// ```
+/// # use shotgun_common::ErrorCode;
+/// let code: ErrorCode = "DuplicateMove".parse().unwrap();
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorCode {
+    /// No `ClientHello` or `Resume` first, or a second one
+    InvalidHandshake,
+    /// The line does not parse
+    InvalidLine,
+    /// A line only the server sends, like `WinGame` or `NewGame`
+    UnexpectedMessage,
+    /// No running game or open challenge with this id for the player
+    UnknownGame,
+    /// The player is out of this game already
+    Eliminated,
+    /// Not a move, or the target is no opponent
+    InvalidMove,
+    /// The player already moved in this round
+    DuplicateMove,
+    /// A request the server can not fulfill, like a match of zero games
+    InvalidRequest,
+    /// Too many requests, games or connections
+    RateLimited,
+    /// The server stops, no new games and the running ones end
+    ShuttingDown,
+    /// An admin closed the connections of the player
+    Kicked,
+    /// An admin ended the game
+    Aborted,
+}
+
 /// All the commands clients can send
 ///
 /// ```
 /// # use shotgun_common::RoundAction;
 /// let action: RoundAction = "Load".parse().unwrap();
 /// ```
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum RoundAction {
     /// Client did not respont this round
     Timeout,
@@ -60,19 +130,48 @@ use RoundAction::*;
 /// # use shotgun_common::ParsedLine;
 /// let action: ParsedLine = "42:Load".parse().unwrap();
 /// ```
-#[derive(Debug,PartialEq)]
+#[derive(Debug,Clone,PartialEq)]
 pub enum ParsedLine {
     /// All the informations about a player
     ClientHello {
         nickname: String,
         programming_language: String,
+        /// Required for nicknames registered on the server
+        token: Option<String>,
     },
     /// The server may update the duration over time
     ServerHello {
         max_round_length: Duration,
+        /// Send it with `Resume` to continue on a new connection after this one dropped
+        session: Option<String>,
+    },
+    /// Instead of a `ClientHello`, continue the session of a dropped connection with all its running games
+    Resume {
+        token: String,
+    },
+    /// The server closes the connection after this
+    HandshakeRejected {
+        reason: String,
+    },
+    /// The server closes the connection after this, telling why
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+    /// A message from the admins, pushed to every connection
+    Announcement {
+        message: String,
     },
 
     RequestNewGame,
+    /// Join a free-for-all game with this many players
+    RequestNewArena { players: u64 },
+    /// Join a team game, every bot sending the same `team` id plays on the same side
+    RequestTeamGame { team: u64 },
+    /// Play a best-of-`games` match series against one opponent
+    RequestNewMatch { games: u64 },
+    /// Play against this specific opponent
+    Challenge { nickname: String },
 
     /// Messages about a round
     MultiplexedMessage {
@@ -84,25 +183,6 @@ pub enum ParsedLine {
 }
 use ParsedLine::*;
 
-pub struct PlayerState {
-    /// Players primary key
-    pub nickname: String,
-    /// How much bullets are loaded?
-    pub ammo_bag: u64,
-    /// Is it still alive?
-    pub alive: bool,
-}
-
-
-/// Server perspective
-pub struct GameState {
-    pub game_id: u64,
-    /// When creating a new game, this player is first
-    pub left_player : Option<PlayerState>,
-    /// As soon as this player joins, the game begins
-    pub right_player: Option<PlayerState>,
-}
-
 #[derive(Debug,PartialEq)]
 pub enum ParseError {
     InvalidAction(String),
@@ -112,15 +192,46 @@ pub enum ParseError {
 }
 use ParseError::*;
 
+impl ParseError {
+    /// The name of the variant, to tell errors apart without their details
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            InvalidAction(_) => "InvalidAction",
+            InvalidGameId(_) => "InvalidGameId",
+            ExpectedValue => "ExpectedValue",
+            InvalidDuration(_) => "InvalidDuration",
+        }
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Decoders hand it on inside an `io::Error`
+impl std::error::Error for ParseError {}
+
 impl ParsedLine {
     fn serialize(&self) -> String {
         match self {
-            &ClientHello { ref nickname, ref programming_language } => format!("Nickname: >{}<>{}", nickname, programming_language),
-            &ServerHello { ref max_round_length } => format!("Shotgun Arena Server v0 :: max round length[ms]: {}", max_round_length.subsec_nanos() / 1_000_000),
-            &RequestNewGame => format!("RequestNewGame"),
-            &MultiplexedMessage { ref game_id, ref action } => {
+            ClientHello { nickname, programming_language, token: None } => format!("Nickname: >{}<>{}", nickname, programming_language),
+            ClientHello { nickname, programming_language, token: Some(token) } => format!("Nickname: >{}<>{}<>{}", nickname, programming_language, token),
+            ServerHello { max_round_length, session: None } => format!("Shotgun Arena Server v0 :: max round length[ms]: {}", max_round_length.as_millis()),
+            ServerHello { max_round_length, session: Some(session) } => format!("Shotgun Arena Server v0 :: max round length[ms]: {} :: session: {}", max_round_length.as_millis(), session),
+            Resume { token } => format!("Resume {{ token: {:?} }}", token),
+            HandshakeRejected { reason } => format!("HandshakeRejected {{ reason: {:?} }}", reason),
+            ParsedLine::Error { code, message } => format!("Error {{ code: {:?}, message: {:?} }}", code, message),
+            Announcement { message } => format!("Announcement {{ message: {:?} }}", message),
+            RequestNewGame => "RequestNewGame".to_string(),
+            RequestNewArena { players } => format!("RequestNewArena {{ players: {} }}", players),
+            RequestTeamGame { team } => format!("RequestTeamGame {{ team: {} }}", team),
+            RequestNewMatch { games } => format!("RequestNewMatch {{ games: {} }}", games),
+            Challenge { nickname } => format!("Challenge {{ nickname: {:?} }}", nickname),
+            MultiplexedMessage { game_id, action } => {
                 // Obmit the PlayerInput(...)
-                if let &PlayerInput(ref command) = action {
+                if let PlayerInput(command) = action {
                     format!("{}:{:?}", game_id, command)
                 } else {
                     format!("{}:{:?}", game_id, action)
@@ -131,7 +242,7 @@ impl ParsedLine {
     /// This works with `MultiplexedMessage` only!
     pub fn answer(&self, new_action: Action) -> Self {
         match self {
-            &MultiplexedMessage { ref game_id, ref action } => MultiplexedMessage { game_id: *game_id, action: new_action },
+            MultiplexedMessage { game_id, .. } => MultiplexedMessage { game_id: *game_id, action: new_action },
             _ => panic!("ParsedLine::answer()"),
         }
     }
@@ -144,29 +255,91 @@ impl std::str::FromStr for ParsedLine {
             return Ok(RequestNewGame)
         }
 
-        if s.starts_with("Nickname: >") {
-            let mut parts = s.split('>').skip(1);
-            let nick = to_parse_error( parts.next() )?;
+        let prefix = "RequestNewArena { players: ";
+        let suffix = " }";
+        if s.starts_with(prefix) && s.ends_with(suffix) {
+            return Ok(RequestNewArena {
+                players: s[prefix.len()..s.len() - suffix.len()].parse().map_err(|_| InvalidAction(format!("invalid RequestNewArena: {:?}", s)))?,
+            })
+        }
+
+        let prefix = "RequestTeamGame { team: ";
+        if s.starts_with(prefix) && s.ends_with(suffix) {
+            return Ok(RequestTeamGame {
+                team: s[prefix.len()..s.len() - suffix.len()].parse().map_err(|_| InvalidAction(format!("invalid RequestTeamGame: {:?}", s)))?,
+            })
+        }
+
+        let prefix = "RequestNewMatch { games: ";
+        if s.starts_with(prefix) && s.ends_with(suffix) {
+            return Ok(RequestNewMatch {
+                games: s[prefix.len()..s.len() - suffix.len()].parse().map_err(|_| InvalidAction(format!("invalid RequestNewMatch: {:?}", s)))?,
+            })
+        }
+
+        if let Some(hello) = s.strip_prefix("Nickname: >") {
+            let mut parts = hello.splitn(3, "<>");
 
             return Ok(ClientHello {
-                nickname: nick[..nick.len()-1].into(),
+                nickname: to_parse_error( parts.next() )?.into(),
                 programming_language: to_parse_error( parts.next() )?.into(),
+                token: parts.next().map(|t| t.into()),
             })
         }
 
+        if let Ok(inner) = strip_frame(s, "HandshakeRejected { reason: ", " }") {
+            let (reason, rest) = split_quoted(inner)?;
+            if !rest.is_empty() {
+                return Err(invalid_action(s))
+            }
+            return Ok(HandshakeRejected { reason })
+        }
+
+        if s.starts_with("Error { ") {
+            let (code, message) = parse_error(s)?;
+            return Ok(ParsedLine::Error { code, message })
+        }
+
+        if let Ok(inner) = strip_frame(s, "Announcement { message: ", " }") {
+            let (message, rest) = split_quoted(inner)?;
+            if !rest.is_empty() {
+                return Err(invalid_action(s))
+            }
+            return Ok(Announcement { message })
+        }
+
+        if let Ok(inner) = strip_frame(s, "Resume { token: ", " }") {
+            let (token, rest) = split_quoted(inner)?;
+            if !rest.is_empty() {
+                return Err(invalid_action(s))
+            }
+            return Ok(Resume { token })
+        }
+
+        if let Ok(inner) = strip_frame(s, "Challenge { nickname: ", " }") {
+            let (nickname, rest) = split_quoted(inner)?;
+            if !rest.is_empty() {
+                return Err(invalid_action(s))
+            }
+            return Ok(Challenge { nickname })
+        }
+
         let banner = "Shotgun Arena Server v0 :: max round length[ms]: ";
         if s.starts_with(banner) {
-            let (_, num) = s.split_at(banner.len());
+            let (_, rest) = s.split_at(banner.len());
+            let mut parts = rest.splitn(2, " :: session: ");
+            let num = parts.next().unwrap();
 
             return Ok(ServerHello {
-                max_round_length: Duration::from_millis( num.parse().map_err(|e| InvalidDuration(e))? ),
+                max_round_length: Duration::from_millis( num.parse().map_err(InvalidDuration)? ),
+                session: parts.next().map(|s| s.into()),
             })
         }
 
         let mut parts = s.splitn(2, ':');
 
         Ok(MultiplexedMessage {
-            game_id: parts.next().unwrap().parse().map_err(|e| InvalidGameId(e))?,
+            game_id: parts.next().unwrap().parse().map_err(InvalidGameId)?,
             action:  parts.next().unwrap().parse()?,
         })
     }
@@ -179,6 +352,16 @@ impl std::str::FromStr for Action {
             "WinGame"       => Ok(WinGame      ),
             "LoseGame"      => Ok(LoseGame     ),
             "ErrorEnd"       => Ok(ErrorEnd      ),
+            "ChallengeAccepted" => Ok(ChallengeAccepted),
+            "ChallengeDeclined" => Ok(ChallengeDeclined),
+            text if text.starts_with("Error { ") => parse_error(text).map(|(code, message)| Action::Error { code, message }),
+            text if text.starts_with("ChallengedBy { ") => parse_challenged_by(text),
+            text if text.starts_with("NewArena { ") => parse_new_arena(text),
+            text if text.starts_with("NewTeamGame { ") => parse_new_team_game(text),
+            text if text.starts_with("NewMatch { ") => parse_new_match(text),
+            text if text.starts_with("MatchResult { ") => parse_match_result(text),
+            text if text.starts_with("TargetedInput { ") => parse_targeted_input(text),
+            text if text.starts_with("ArenaResult { ") => parse_arena_result(text),
             text => {
                 let prefix = "NewGame { player_name_a: \"";
                 let suffix = "\" }";
@@ -218,6 +401,34 @@ impl std::str::FromStr for Action {
         }
     }
 }
+impl std::str::FromStr for ErrorCode {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use ErrorCode::*;
+        match s {
+            "InvalidHandshake"  => Ok(InvalidHandshake ),
+            "InvalidLine"       => Ok(InvalidLine      ),
+            "UnexpectedMessage" => Ok(UnexpectedMessage),
+            "UnknownGame"       => Ok(UnknownGame      ),
+            "Eliminated"        => Ok(Eliminated       ),
+            "InvalidMove"       => Ok(InvalidMove      ),
+            "DuplicateMove"     => Ok(DuplicateMove    ),
+            "InvalidRequest"    => Ok(InvalidRequest   ),
+            "RateLimited"       => Ok(RateLimited      ),
+            "ShuttingDown"      => Ok(ShuttingDown     ),
+            "Kicked"            => Ok(Kicked           ),
+            "Aborted"           => Ok(Aborted          ),
+            text => Err(InvalidAction(format!("invalid ErrorCode: {:?}", text))),
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
 impl std::str::FromStr for RoundAction {
     type Err = ParseError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
@@ -246,10 +457,160 @@ pub fn to_io_err<T>(o: Option<T>) -> Result<T, std::io::Error> {
     use std::io;
     match o {
         Some(v) => Ok(v),
-        None => Err(io::Error::new(io::ErrorKind::Other, "expected value")),
+        None => Err(io::Error::other("expected value")),
     }
 }
 
+fn invalid_action(text: &str) -> ParseError {
+    InvalidAction(format!("invalid Action: {:?}", text))
+}
+
+/// Split off a leading `"quoted"` string as `{:?}` writes it, returns the unescaped content and the rest
+fn split_quoted(text: &str) -> Result<(String, &str), ParseError> {
+    let mut chars = text.strip_prefix('"').ok_or(ExpectedValue)?.char_indices();
+    let mut content = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((content, &text[i + 2..])),
+            '\\' => content.push(match chars.next().ok_or(ExpectedValue)?.1 {
+                'n' => '\n',
+                'r' => '\r',
+                't' => '\t',
+                '0' => '\0',
+                'u' => {
+                    let rest = chars.as_str().strip_prefix('{').ok_or(ExpectedValue)?;
+                    let hex = &rest[..rest.find('}').ok_or(ExpectedValue)?];
+                    // Skip the braces and the digits
+                    chars.nth(hex.len() + 1);
+                    u32::from_str_radix(hex, 16).ok().and_then(char::from_u32).ok_or(ExpectedValue)?
+                }
+                // `\"`, `\\` and `\'`
+                escaped => escaped,
+            }),
+            c => content.push(c),
+        }
+    }
+    Err(ExpectedValue)
+}
+
+/// Strip `prefix` and `suffix` or fail with `InvalidAction`
+fn strip_frame<'a>(text: &'a str, prefix: &str, suffix: &str) -> Result<&'a str, ParseError> {
+    if text.starts_with(prefix) && text.ends_with(suffix) && text.len() >= prefix.len() + suffix.len() {
+        Ok(&text[prefix.len()..text.len() - suffix.len()])
+    } else {
+        Err(invalid_action(text))
+    }
+}
+
+/// Split off a leading `["quoted", "list"]`, returns the content and the rest
+fn split_quoted_list(text: &str) -> Result<(Vec<String>, &str), ParseError> {
+    let mut rest = text.strip_prefix('[').ok_or(ExpectedValue)?;
+    let mut list = Vec::new();
+    while !rest.starts_with(']') {
+        let (item, tail) = split_quoted(rest)?;
+        list.push(item);
+        rest = match tail.strip_prefix(", ") {
+            Some(next) => next,
+            None if tail.starts_with(']') => tail,
+            None => return Err(ExpectedValue),
+        };
+    }
+    Ok((list, &rest[1..]))
+}
+
+fn parse_new_arena(text: &str) -> Result<Action, ParseError> {
+    let inner = strip_frame(text, "NewArena { players: ", " }")?;
+    let (players, rest) = split_quoted_list(inner)?;
+    if !rest.is_empty() {
+        return Err(invalid_action(text))
+    }
+    Ok(NewArena { players })
+}
+
+fn parse_new_team_game(text: &str) -> Result<Action, ParseError> {
+    let inner = strip_frame(text, "NewTeamGame { team_a: ", " }")?;
+    let (team_a, rest) = split_quoted_list(inner)?;
+    let rest = rest.strip_prefix(", team_b: ").ok_or_else(|| invalid_action(text))?;
+    let (team_b, rest) = split_quoted_list(rest)?;
+    if !rest.is_empty() {
+        return Err(invalid_action(text))
+    }
+    Ok(NewTeamGame { team_a, team_b })
+}
+
+fn parse_new_match(text: &str) -> Result<Action, ParseError> {
+    let games = strip_frame(text, "NewMatch { games: ", " }")?;
+    Ok(NewMatch {
+        games: games.parse().map_err(|_| invalid_action(text))?,
+    })
+}
+
+fn parse_match_result(text: &str) -> Result<Action, ParseError> {
+    let inner = strip_frame(text, "MatchResult { won: ", " }")?;
+    let mut parts = inner.splitn(2, ", lost: ");
+    Ok(MatchResult {
+        won: to_parse_error( parts.next() )?.parse().map_err(|_| invalid_action(text))?,
+        lost: to_parse_error( parts.next() )?.parse().map_err(|_| invalid_action(text))?,
+    })
+}
+
+/// The fields of `Error { code: …, message: "…" }`, as a line or an action
+fn parse_error(text: &str) -> Result<(ErrorCode, String), ParseError> {
+    let inner = strip_frame(text, "Error { code: ", " }")?;
+    let mut parts = inner.splitn(2, ", message: ");
+    let code = to_parse_error( parts.next() )?.parse()?;
+    let (message, rest) = split_quoted(to_parse_error( parts.next() )?)?;
+    if !rest.is_empty() {
+        return Err(invalid_action(text))
+    }
+    Ok((code, message))
+}
+
+fn parse_challenged_by(text: &str) -> Result<Action, ParseError> {
+    let inner = strip_frame(text, "ChallengedBy { nickname: ", " }")?;
+    let (nickname, rest) = split_quoted(inner)?;
+    if !rest.is_empty() {
+        return Err(invalid_action(text))
+    }
+    Ok(ChallengedBy { nickname })
+}
+
+fn parse_targeted_input(text: &str) -> Result<Action, ParseError> {
+    let inner = strip_frame(text, "TargetedInput { action: ", " }")?;
+    let mut parts = inner.splitn(2, ", target: ");
+    let action = to_parse_error( parts.next() )?.parse()?;
+    let (target, rest) = split_quoted(to_parse_error( parts.next() )?)?;
+    if !rest.is_empty() {
+        return Err(invalid_action(text))
+    }
+    Ok(TargetedInput { action, target })
+}
+
+fn parse_arena_result(text: &str) -> Result<Action, ParseError> {
+    let mut rest = strip_frame(text, "ArenaResult { moves: [", "] }")?;
+    let mut moves = Vec::new();
+    while !rest.is_empty() {
+        let (player, tail) = split_quoted(rest)?;
+        let tail = tail.strip_prefix(": ").ok_or_else(|| invalid_action(text))?;
+        let end = tail.find([' ', ',']).unwrap_or(tail.len());
+        let action = tail[..end].parse()?;
+        let mut tail = &tail[end..];
+        let mut target = None;
+        if let Some(quoted) = tail.strip_prefix(" -> ") {
+            let (t, after) = split_quoted(quoted)?;
+            target = Some(t);
+            tail = after;
+        }
+        moves.push(PlayerMove { player, action, target });
+        rest = match tail.strip_prefix(", ") {
+            Some(next) => next,
+            None if tail.is_empty() => tail,
+            None => return Err(invalid_action(text)),
+        };
+    }
+    Ok(ArenaResult { moves })
+}
+
 /// Offset must be at '" and end will be at '"' again
 fn follow_quoted_str(buf: &str, offset: usize) -> usize {
     let mut escape = false;
@@ -262,7 +623,7 @@ fn follow_quoted_str(buf: &str, offset: usize) -> usize {
             b'\\' => { escape = !escape; },
             b'"' if escape => { escape = !escape; },
             b'"' => return i,
-            _ => { () },
+            _ => { escape = false; },
         };
     }
 
@@ -271,7 +632,7 @@ fn follow_quoted_str(buf: &str, offset: usize) -> usize {
 
 #[cfg(test)]
 mod tests {
-    use ::*;
+    use crate::*;
 
     #[test]
     fn parse_client_hello() {
@@ -279,25 +640,89 @@ mod tests {
         let obj = ClientHello {
             nickname: "dns2utf8".into(),
             programming_language: "rust".into(),
+            token: None,
         };
         assert_eq!(obj, s.parse().unwrap());
     }
 
+    #[test]
+    fn parse_client_hello_with_token() {
+        let s = "Nickname: >dns2utf8<>rust<>secret";
+        let obj = ClientHello {
+            nickname: "dns2utf8".into(),
+            programming_language: "rust".into(),
+            token: Some("secret".into()),
+        };
+        assert_eq!(obj, s.parse().unwrap());
+    }
+    #[test]
+    fn encode_client_hello_with_token() {
+        let s = "Nickname: >dns2utf8<>rust<>secret";
+        let obj = ClientHello {
+            nickname: "dns2utf8".into(),
+            programming_language: "rust".into(),
+            token: Some("secret".into()),
+        };
+        assert_eq!(s, obj.serialize());
+    }
+
     #[test]
     fn encode_client_hello() {
         let s = "Nickname: >dns2utf8<>rust";
         let obj = ClientHello {
             nickname: "dns2utf8".into(),
             programming_language: "rust".into(),
+            token: None,
         };
         assert_eq!(s, obj.serialize());
     }
 
+    #[test]
+    fn parse_handshake_rejected() {
+        let s = "HandshakeRejected { reason: \"invalid token\" }";
+        assert_eq!(HandshakeRejected { reason: "invalid token".into() }, s.parse().unwrap());
+    }
+    #[test]
+    fn encode_handshake_rejected() {
+        let s = "HandshakeRejected { reason: \"invalid token\" }";
+        assert_eq!(s, HandshakeRejected { reason: "invalid token".into() }.serialize());
+    }
+
+    #[test]
+    fn parse_error() {
+        let s = "Error { code: InvalidHandshake, message: \"handshake required\" }";
+        assert_eq!(ParsedLine::Error { code: ErrorCode::InvalidHandshake, message: "handshake required".into() }, s.parse().unwrap());
+    }
+    #[test]
+    fn encode_error() {
+        let s = "Error { code: RateLimited, message: \"too many games\" }";
+        assert_eq!(s, ParsedLine::Error { code: ErrorCode::RateLimited, message: "too many games".into() }.serialize());
+    }
+    #[test]
+    fn multiplexed_error() {
+        let s = "7:Error { code: DuplicateMove, message: \"already moved\" }";
+        let obj = MultiplexedMessage { game_id: 7, action: Action::Error { code: ErrorCode::DuplicateMove, message: "already moved".into() } };
+        assert_eq!(obj, s.parse().unwrap());
+        assert_eq!(s, obj.serialize());
+    }
+    #[test]
+    fn announcement() {
+        let s = "Announcement { message: \"restart at noon\" }";
+        let obj = Announcement { message: "restart at noon".into() };
+        assert_eq!(obj, s.parse().unwrap());
+        assert_eq!(s, obj.serialize());
+    }
+    #[test]
+    fn parse_unknown_error_code() {
+        assert!("Error { code: Gibberish, message: \"\" }".parse::<ParsedLine>().is_err());
+    }
+
     #[test]
     fn parse_server_hello() {
         let s = "Shotgun Arena Server v0 :: max round length[ms]: 200";
         let obj = ServerHello {
             max_round_length: Duration::from_millis(200),
+            session: None,
         };
         assert_eq!(obj, s.parse().unwrap());
     }
@@ -306,10 +731,52 @@ mod tests {
         let s = "Shotgun Arena Server v0 :: max round length[ms]: 200";
         let obj = ServerHello {
             max_round_length: Duration::from_millis(200),
+            session: None,
+        };
+        assert_eq!(s, obj.serialize());
+    }
+
+    #[test]
+    fn server_hello_above_a_second() {
+        let s = "Shotgun Arena Server v0 :: max round length[ms]: 1500";
+        let obj = ServerHello {
+            max_round_length: Duration::from_millis(1500),
+            session: None,
+        };
+        assert_eq!(s, obj.serialize());
+        assert_eq!(obj, s.parse().unwrap());
+    }
+
+    #[test]
+    fn parse_server_hello_with_session() {
+        let s = "Shotgun Arena Server v0 :: max round length[ms]: 200 :: session: 0123abcd";
+        let obj = ServerHello {
+            max_round_length: Duration::from_millis(200),
+            session: Some("0123abcd".into()),
+        };
+        assert_eq!(obj, s.parse().unwrap());
+    }
+    #[test]
+    fn encode_server_hello_with_session() {
+        let s = "Shotgun Arena Server v0 :: max round length[ms]: 200 :: session: 0123abcd";
+        let obj = ServerHello {
+            max_round_length: Duration::from_millis(200),
+            session: Some("0123abcd".into()),
         };
         assert_eq!(s, obj.serialize());
     }
 
+    #[test]
+    fn parse_resume() {
+        let s = "Resume { token: \"0123abcd\" }";
+        assert_eq!(Resume { token: "0123abcd".into() }, s.parse().unwrap());
+    }
+    #[test]
+    fn encode_resume() {
+        let s = "Resume { token: \"0123abcd\" }";
+        assert_eq!(s, Resume { token: "0123abcd".into() }.serialize());
+    }
+
     #[test]
     fn parse_request_new_game() {
         let s = "RequestNewGame";
@@ -386,6 +853,169 @@ mod tests {
         assert_eq!(resp, obj);
     }
 
+    #[test]
+    fn parse_request_new_arena() {
+        let s = "RequestNewArena { players: 4 }";
+        assert_eq!(RequestNewArena { players: 4 }, s.parse().unwrap());
+    }
+    #[test]
+    fn encode_request_new_arena() {
+        let s = "RequestNewArena { players: 4 }";
+        assert_eq!(s, RequestNewArena { players: 4 }.serialize());
+    }
+
+    #[test]
+    fn parse_new_arena() {
+        let resp = "NewArena { players: [\"me\", \"you\", \"them\"] }".parse().unwrap();
+        let obj = NewArena { players: vec!["me".into(), "you".into(), "them".into()] };
+        assert_eq!(obj, resp);
+    }
+    #[test]
+    fn encode_new_arena() {
+        let resp = "NewArena { players: [\"me\", \"you\", \"them\"] }";
+        let obj = format!("{:?}", NewArena { players: vec!["me".into(), "you".into(), "them".into()] });
+        assert_eq!(resp, obj);
+    }
+
+    #[test]
+    fn parse_empty_new_arena() {
+        assert_eq!(Ok(NewArena { players: vec![] }), "NewArena { players: [] }".parse());
+    }
+    #[test]
+    fn parse_invalid_new_arena() {
+        assert!("NewArena { players: [\"me\" \"you\"] }".parse::<Action>().is_err());
+        assert!("NewArena { players: [\"me\"".parse::<Action>().is_err());
+    }
+
+    #[test]
+    fn parse_request_team_game() {
+        let s = "RequestTeamGame { team: 7 }";
+        assert_eq!(RequestTeamGame { team: 7 }, s.parse().unwrap());
+    }
+    #[test]
+    fn encode_request_team_game() {
+        let s = "RequestTeamGame { team: 7 }";
+        assert_eq!(s, RequestTeamGame { team: 7 }.serialize());
+    }
+
+    #[test]
+    fn parse_new_team_game() {
+        let resp = "NewTeamGame { team_a: [\"a\", \"b\"], team_b: [\"c\", \"d\"] }".parse().unwrap();
+        let obj = NewTeamGame { team_a: vec!["a".into(), "b".into()], team_b: vec!["c".into(), "d".into()] };
+        assert_eq!(obj, resp);
+    }
+    #[test]
+    fn encode_new_team_game() {
+        let resp = "NewTeamGame { team_a: [\"a\", \"b\"], team_b: [\"c\", \"d\"] }";
+        let obj = format!("{:?}", NewTeamGame { team_a: vec!["a".into(), "b".into()], team_b: vec!["c".into(), "d".into()] });
+        assert_eq!(resp, obj);
+    }
+
+    #[test]
+    fn parse_request_new_match() {
+        let s = "RequestNewMatch { games: 5 }";
+        assert_eq!(RequestNewMatch { games: 5 }, s.parse().unwrap());
+    }
+    #[test]
+    fn encode_request_new_match() {
+        let s = "RequestNewMatch { games: 5 }";
+        assert_eq!(s, RequestNewMatch { games: 5 }.serialize());
+    }
+
+    #[test]
+    fn parse_new_match() {
+        let s = "12:NewMatch { games: 5 }";
+        let obj = MultiplexedMessage { game_id: 12, action: NewMatch { games: 5 } };
+        assert_eq!(Ok(obj), s.parse());
+    }
+    #[test]
+    fn encode_new_match() {
+        let obj = MultiplexedMessage { game_id: 12, action: NewMatch { games: 5 } };
+        assert_eq!("12:NewMatch { games: 5 }", obj.serialize());
+    }
+
+    #[test]
+    fn parse_match_result() {
+        assert_eq!(Ok(MatchResult { won: 3, lost: 1 }), "MatchResult { won: 3, lost: 1 }".parse());
+    }
+    #[test]
+    fn encode_match_result() {
+        assert_eq!("MatchResult { won: 3, lost: 1 }", format!("{:?}", MatchResult { won: 3, lost: 1 }));
+    }
+
+    #[test]
+    fn parse_challenge() {
+        let s = "Challenge { nickname: \"coward_bot\" }";
+        assert_eq!(Challenge { nickname: "coward_bot".into() }, s.parse().unwrap());
+    }
+    #[test]
+    fn encode_challenge() {
+        let s = "Challenge { nickname: \"coward_bot\" }";
+        assert_eq!(s, Challenge { nickname: "coward_bot".into() }.serialize());
+    }
+
+    #[test]
+    fn parse_challenged_by() {
+        let s = "5:ChallengedBy { nickname: \"me\" }";
+        let obj = MultiplexedMessage { game_id: 5, action: ChallengedBy { nickname: "me".into() } };
+        assert_eq!(Ok(obj), s.parse());
+    }
+    #[test]
+    fn encode_challenged_by() {
+        let obj = MultiplexedMessage { game_id: 5, action: ChallengedBy { nickname: "me".into() } };
+        assert_eq!("5:ChallengedBy { nickname: \"me\" }", obj.serialize());
+    }
+    #[test]
+    fn parse_challenge_accepted() {
+        assert_eq!(Ok(ChallengeAccepted), "ChallengeAccepted".parse())
+    }
+    #[test]
+    fn parse_challenge_declined() {
+        assert_eq!(Ok(ChallengeDeclined), "ChallengeDeclined".parse())
+    }
+
+    #[test]
+    fn parse_targeted_input() {
+        let resp = "3:TargetedInput { action: Shoot, target: \"you\" }".parse();
+        let obj = MultiplexedMessage {
+            game_id: 3,
+            action: TargetedInput { action: Shoot, target: "you".into() },
+        };
+        assert_eq!(Ok(obj), resp);
+    }
+    #[test]
+    fn encode_targeted_input() {
+        let pl = MultiplexedMessage {
+            game_id: 3,
+            action: TargetedInput { action: Shoot, target: "you".into() },
+        };
+        assert_eq!("3:TargetedInput { action: Shoot, target: \"you\" }", pl.serialize());
+    }
+
+    #[test]
+    fn parse_arena_result() {
+        let resp = "ArenaResult { moves: [\"me\": Shoot -> \"you\", \"you\": Duck, \"them\": Klick -> \"me\"] }".parse().unwrap();
+        let obj = ArenaResult { moves: vec![
+            PlayerMove { player: "me".into(), action: Shoot, target: Some("you".into()) },
+            PlayerMove { player: "you".into(), action: Duck, target: None },
+            PlayerMove { player: "them".into(), action: Klick, target: Some("me".into()) },
+        ] };
+        assert_eq!(obj, resp);
+    }
+    #[test]
+    fn encode_arena_result() {
+        let resp = "ArenaResult { moves: [\"me\": Shoot -> \"you\", \"you\": Duck] }";
+        let obj = format!("{:?}", ArenaResult { moves: vec![
+            PlayerMove { player: "me".into(), action: Shoot, target: Some("you".into()) },
+            PlayerMove { player: "you".into(), action: Duck, target: None },
+        ] });
+        assert_eq!(resp, obj);
+    }
+    #[test]
+    fn parse_invalid_arena_result() {
+        assert!("ArenaResult { moves: [\"me\" Shoot] }".parse::<Action>().is_err());
+    }
+
     #[test]
     fn parse_timeout() {
         assert_eq!(Ok(Timeout), "Timeout".parse())
@@ -423,11 +1053,23 @@ mod tests {
         assert_eq!(Err(InvalidAction("invalid Action: \"blubb\"".to_string())), "blubb".parse::<Action>())
     }
 
+    #[test]
+    fn quoted_strings_round_trip() {
+        let text = "say \"hi\" \\ ok\n\u{7}";
+        for line in [
+            Announcement { message: text.into() },
+            HandshakeRejected { reason: text.into() },
+            ParsedLine::Error { code: ErrorCode::InvalidLine, message: text.into() },
+        ] {
+            assert_eq!(Ok(line.clone()), line.serialize().parse());
+        }
+    }
+
     #[test]
     fn check_follow_quoted_str() {
         let s = "ab: \"blubbeln zu zweit\", def: \"asldfj\"";
         let (start, end) = (4, 23);
-        assert_eq!(end, follow_quoted_str(&s, start));
+        assert_eq!(end, follow_quoted_str(s, start));
         assert_eq!("\"blubbeln zu zweit\"", &s[start..end]);
     }
 }
@@ -0,0 +1,32 @@
+//! Leveled logging for the gameserver and the bots, as text or one JSON object per line
+//!
+//! Connections and games get their own spans, so every line carries the connection id,
+//! the nickname and the game id it belongs to.
+
+use std::io;
+
+use tracing_subscriber::EnvFilter;
+
+/// Install the global logger writing to stderr
+///
+/// `level` is a filter like `info`, `debug` or `shotgun_gameserver=trace,info`.
+pub fn init(level: &str, json: bool) -> Result<(), String> {
+    let filter = EnvFilter::try_new(level).map_err(|e| format!("invalid log level {:?}: {}", level, e))?;
+    let builder = tracing_subscriber::fmt().with_env_filter(filter).with_writer(io::stderr);
+    let result = if json {
+        builder.json().with_current_span(true).with_span_list(true).try_init()
+    } else {
+        builder.try_init()
+    };
+    result.map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reject_invalid_level() {
+        assert!(init("shotgun_common=loud", false).is_err());
+    }
+}
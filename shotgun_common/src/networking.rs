@@ -1,31 +1,31 @@
-//use std::io::prelude::*;
-//use std::net::TcpStream;
+//! The line based protocol on top of any byte stream
+//!
+//! `lines` frames a connection, `handshake` and `serve` are the server side and `Client` talks to it.
+//! Every line after the handshake carries its game id, so replies go out as soon as they are ready
+//! and the server may push lines nobody asked for.
+
+use std::future::Future;
 use std::io;
 use std::str;
-use bytes::{BytesMut, BufMut};
-use futures::{future, Future, BoxFuture, Stream, Sink};
-use tokio_io::codec::{Encoder, Decoder};
-use tokio_proto::pipeline::{ServerProto, ClientProto};
-
-use ::*;
+use bytes::BytesMut;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use futures::stream::FuturesUnordered;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Decoder, Encoder, Framed};
 
-pub enum ConnectionState {
-    Connecting,
-    Handshake,
-    ArenaMode,
-}
-use self::ConnectionState::*;
+use crate::*;
 
+/// Longer lines are refused, a client can not make the server buffer without end
+const MAX_LINE: usize = 64 * 1024;
 
 pub struct LineCodec;
 
-impl Encoder for LineCodec {
-    type Item = ParsedLine;
+impl Encoder<ParsedLine> for LineCodec {
     type Error = io::Error;
 
     fn encode(&mut self, msg: ParsedLine, buf: &mut BytesMut) -> io::Result<()> {
-        buf.extend(msg.serialize().as_bytes());
-        buf.extend(b"\n");
+        buf.extend_from_slice(msg.serialize().as_bytes());
+        buf.extend_from_slice(b"\n");
         Ok(())
     }
 }
@@ -35,20 +35,24 @@ impl Decoder for LineCodec {
     type Error = io::Error;
 
     fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Self::Item>> {
-        if let Some(i) = buf.iter().position(|&b| b == b'\n') {
+        let end = buf.iter().position(|&b| b == b'\n');
+        if end.unwrap_or(buf.len()) > MAX_LINE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"))
+        }
+        if let Some(i) = end {
             // remove the serialized frame from the buffer.
             let line = buf.split_to(i);
 
             // Also remove the '\n'
-            buf.split_to(1);
+            let _ = buf.split_to(1);
 
             // Turn this data into a UTF string and return it in a Frame.
             let s = str::from_utf8(&line)
-                .map_err(|e| io::Error::new(io::ErrorKind::Other, "invalid UTF-8") )
+                .map_err(|_| io::Error::other("invalid UTF-8") )
                 ?.to_string();
 
             let line = s.parse()
-                .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)) )?;
+                .map_err(|e: ParseError| io::Error::other(e) )?;
 
             Ok(Some(line))
         } else {
@@ -57,104 +61,285 @@ impl Decoder for LineCodec {
     }
 }
 
-
-pub struct LineProto {
-    client_hello: Option<ParsedLine>,
+/// One `ParsedLine` per line of the connection
+pub fn lines<T: AsyncRead + AsyncWrite>(io: T) -> Framed<T, LineCodec> {
+    Framed::new(io, LineCodec)
 }
-impl LineProto {
-    pub fn new() -> LineProto {
-        LineProto {
-            client_hello: None,
+
+/// Wait for the `ClientHello` or `Resume` on a freshly connected transport of any kind
+///
+/// Anything else is answered with an `Error` before the connection is closed.
+pub async fn handshake<T>(transport: &mut T) -> io::Result<ParsedLine>
+    where T: Stream<Item = io::Result<ParsedLine>> + Sink<ParsedLine, Error = io::Error> + Unpin
+{
+    let (code, e) = match transport.next().await {
+        Some(Ok(hello @ ClientHello { .. })) | Some(Ok(hello @ Resume { .. })) => {
+            debug!("received client handshake");
+            return Ok(hello)
         }
-    }
+        Some(Err(e)) => {
+            warn!(error = %e, "handshake failed");
+            (ErrorCode::InvalidLine, e)
+        }
+        None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "closed before the handshake")),
+        Some(Ok(line)) => {
+            // The client sent an unexpected handshake,
+            // error out the connection
+            warn!(line = ?line, "invalid client handshake");
+            (ErrorCode::InvalidHandshake, io::Error::other("invalid handshake"))
+        }
+    };
+    let _ = transport.send(ParsedLine::Error { code, message: e.to_string() }).await;
+    Err(e)
 }
-use tokio_io::{AsyncRead, AsyncWrite};
-use tokio_io::codec::Framed;
-
-impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for LineProto {
-    /// For this protocol style, `Request` matches the codec `In` type
-    type Request = ParsedLine;
-
-    /// For this protocol style, `Response` matches the coded `Out` type
-    type Response = ParsedLine;
-
-    // `Framed<T, LineCodec>` is the return value of
-    // `io.framed(LineCodec)`
-    type Transport = Framed<T, LineCodec>;
-    type BindTransport = Box<Future<Item = Self::Transport,
-                                   Error = io::Error>>;
-    fn bind_transport(&self, io: T) -> Self::BindTransport {
-        let mut transport = io.framed(LineCodec);
-
-        // FIXME this is dark magic!
-        let selfptr: *mut LineProto = unsafe {
-            std::mem::transmute::<*const LineProto, *mut LineProto>(self)
-        };
-
-        //transport.start_send(ServerHello { max_round_length: Duration::from_millis(200), }).poll_complete();
-
-        let handshake = transport.into_future()
-            // If the transport errors out, we don't care about
-            // the transport anymore, so just keep the error
-            .map_err(|(e, _t)| { println!("invalid Handshake: {:?}", e); e})
-            .and_then(move |(line, transport)| {
-                // type `(std::option::Option<ParsedLine>, tokio_io::codec::Framed<T, networking::LineCodec>)`
-
-                // A line has been received, check to see if it
-                // is the handshake
-                match line {
-                    Some(ClientHello { ref nickname, ref programming_language }) => {
-                        println!("SERVER: received client handshake");
-                        // Send back the acknowledgement
-                        let ret = transport.send(ServerHello {
-                            max_round_length: Duration::from_millis(200),
-                        });
-                        unsafe {
-                            // FIXME this is dark magic!
-                            (*selfptr).client_hello = Some(ClientHello {
-                                nickname: nickname.clone(),
-                                programming_language: programming_language.clone(),
-                            });
-                        }
-                        Box::new(ret) as Self::BindTransport
-                    }
-                    _ => {
-                        // The client sent an unexpected handshake,
-                        // error out the connection
-                        println!("SERVER: client handshake INVALID");
-                        let err = io::Error::new(io::ErrorKind::Other,
-                                                 "invalid handshake");
-                        let ret = future::err(err);
-                        Box::new(ret) as Self::BindTransport
+
+/// Answer every request of a connection after the handshake, the `hello` is the first request
+///
+/// Every request gets exactly one reply as soon as it is ready, in between the lines
+/// from `pushed` are sent. Once the client stops sending the connection is done, the open
+/// replies are dropped, so nobody waits in a lobby for it. It is closed right away after an `Error`, replied or pushed,
+/// and after a `HandshakeRejected`.
+/// `reject` answers a line that could not be read, e.g. with such an `Error`.
+pub async fn serve<T, P, F, R, E>(mut transport: T, hello: ParsedLine, mut pushed: P, mut call: F, mut reject: E) -> io::Result<()>
+    where T: Stream<Item = io::Result<ParsedLine>> + Sink<ParsedLine, Error = io::Error> + Unpin,
+          P: Stream<Item = ParsedLine> + Unpin,
+          F: FnMut(ParsedLine) -> R,
+          R: Future<Output = io::Result<ParsedLine>>,
+          E: FnMut(io::Error) -> io::Result<ParsedLine>,
+{
+    // Nothing is pushed before the `ServerHello`
+    if send_reply(&mut transport, call(hello).await?).await? {
+        return Ok(())
+    }
+
+    let mut replies = FuturesUnordered::new();
+    // `Framed` ends the stream once after a line it could not decode, then reads on
+    let mut rejected = false;
+    loop {
+        tokio::select! {
+            // A round result goes out before the outcome of the game pushed with it
+            biased;
+            Some(reply) = replies.next() => if send_reply(&mut transport, reply?).await? {
+                return Ok(())
+            },
+            Some(line) = pushed.next() => if send_reply(&mut transport, line).await? {
+                return Ok(())
+            },
+            request = transport.next() => match request {
+                Some(Ok(request)) => {
+                    rejected = false;
+                    replies.push(call(request));
+                }
+                Some(Err(e)) => {
+                    rejected = true;
+                    if send_reply(&mut transport, reject(e)?).await? {
+                        return Ok(())
                     }
                 }
-            });
-
-        Box::new(handshake)
+                None if rejected => rejected = false,
+                None => return Ok(()),
+            },
+        }
     }
 }
 
+/// Close the connection after an unparsable line, telling the client why
+pub fn close_on_invalid_line(e: io::Error) -> io::Result<ParsedLine> {
+    Ok(ParsedLine::Error { code: ErrorCode::InvalidLine, message: e.to_string() })
+}
 
-/// Not so much implemented for Clients here, pass everything along
-impl<T: AsyncRead + AsyncWrite + 'static> ClientProto<T> for LineProto {
-    type Request = ParsedLine;
-    type Response = ParsedLine;
-
-    /// `Framed<T, LineCodec>` is the return value of `io.framed(LineCodec)`
-    type Transport = Framed<T, LineCodec>;
-    type BindTransport = Result<Self::Transport, io::Error>;
+/// Send the reply, true if the connection closes after it
+async fn send_reply<T>(transport: &mut T, reply: ParsedLine) -> io::Result<bool>
+    where T: Sink<ParsedLine, Error = io::Error> + Unpin
+{
+    let closing = matches!(reply, ParsedLine::Error { .. } | ParsedLine::HandshakeRejected { .. });
+    transport.send(reply).await?;
+    Ok(closing)
+}
 
-    fn bind_transport(&self, io: T) -> Self::BindTransport {
-        Ok(io.framed(LineCodec))
-    }
+/// The client side, every request waits for the next line
+///
+/// That is its reply unless the server pushed something in between, like the `WinGame` after a `RoundResult`.
+pub struct Client<T> {
+    lines: Framed<T, LineCodec>,
 }
 
+impl<T: AsyncRead + AsyncWrite + Unpin> Client<T> {
+    pub fn new(io: T) -> Client<T> {
+        Client { lines: lines(io) }
+    }
 
+    pub async fn call(&mut self, request: ParsedLine) -> io::Result<ParsedLine> {
+        debug!(request = ?request, "sending");
+        self.lines.send(request).await?;
+        self.receive().await
+    }
 
+    /// The next line from the server, without sending anything
+    pub async fn receive(&mut self) -> io::Result<ParsedLine> {
+        match self.lines.next().await {
+            Some(response) => {
+                let response = response?;
+                debug!(response = ?response, "received");
+                Ok(response)
+            }
+            None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by the server")),
+        }
+    }
+}
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
     #[test]
-    fn it_works() {
+    fn decode_error_carries_parse_error() {
+        let mut buf = BytesMut::from(&b"Gibberish\n"[..]);
+        let err = LineCodec.decode(&mut buf).unwrap_err();
+        let parse_error = err.get_ref().and_then(|e| e.downcast_ref::<ParseError>()).unwrap();
+        assert_eq!("InvalidGameId", parse_error.kind());
+    }
+
+    #[test]
+    fn long_line_is_refused() {
+        let mut buf = BytesMut::from(&vec![b'a'; MAX_LINE][..]);
+        assert!(LineCodec.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(b"a\n");
+        assert_eq!(io::ErrorKind::InvalidData, LineCodec.decode(&mut buf).unwrap_err().kind());
+    }
+
+    #[tokio::test]
+    async fn serve_replies_and_pushes() {
+        let (client, server) = tokio::io::duplex(4096);
+        let (push, pushed) = futures::channel::mpsc::unbounded();
+        let (answer, answered) = futures::channel::oneshot::channel();
+        let mut answered = Some(answered);
+        tokio::spawn(async move {
+            let mut transport = lines(server);
+            let hello = handshake(&mut transport).await.unwrap();
+            serve(transport, hello, pushed, |request| {
+                // The new game is found only after the next request
+                let answered = match request {
+                    RequestNewGame => answered.take(),
+                    _ => None,
+                };
+                async move {
+                    match request {
+                        ClientHello { .. } => Ok(ServerHello { max_round_length: Duration::from_millis(500), session: None }),
+                        RequestNewGame => answered.unwrap().await.map_err(io::Error::other),
+                        Challenge { .. } => Ok(MultiplexedMessage { game_id: 2, action: Action::ChallengeDeclined }),
+                        other => Err(io::Error::other(format!("unexpected {:?}", other))),
+                    }
+                }
+            }, close_on_invalid_line).await
+        });
+
+        let mut client = Client::new(client);
+        let hello = client.call(ClientHello { nickname: "me".into(), programming_language: "Rust".into(), token: None }).await.unwrap();
+        assert_eq!(ServerHello { max_round_length: Duration::from_millis(500), session: None }, hello);
+
+        client.lines.send(RequestNewGame).await.unwrap();
+        let declined = client.call(Challenge { nickname: "you".into() }).await.unwrap();
+        assert_eq!(MultiplexedMessage { game_id: 2, action: Action::ChallengeDeclined }, declined);
+
+        let new_game = MultiplexedMessage { game_id: 1, action: Action::NewGame { player_name_a: "me".into(), player_name_b: "you".into() } };
+        answer.send(new_game.clone()).unwrap();
+        assert_eq!(new_game, client.receive().await.unwrap());
+
+        push.unbounded_send(MultiplexedMessage { game_id: 1, action: Action::WinGame }).unwrap();
+        assert_eq!(MultiplexedMessage { game_id: 1, action: Action::WinGame }, client.receive().await.unwrap());
+        assert!(client.call(RequestNewArena { players: 3 }).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn invalid_handshake_is_answered() {
+        let (client, server) = tokio::io::duplex(4096);
+        let handshaking = tokio::spawn(async move { handshake(&mut lines(server)).await.is_err() });
+
+        let mut client = Client::new(client);
+        match client.call(RequestNewGame).await.unwrap() {
+            ParsedLine::Error { code: ErrorCode::InvalidHandshake, .. } => {},
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(handshaking.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn serve_reads_on_after_rejected_line() {
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            let mut transport = lines(server);
+            let hello = handshake(&mut transport).await.unwrap();
+            let rejected = |e: io::Error| Ok(MultiplexedMessage { game_id: 0, action: Action::Error { code: ErrorCode::InvalidLine, message: e.to_string() } });
+            serve(transport, hello, futures::stream::pending(), |request| async move {
+                match request {
+                    ClientHello { .. } => Ok(ServerHello { max_round_length: Duration::from_millis(500), session: None }),
+                    _ => Ok(MultiplexedMessage { game_id: 1, action: Action::ChallengeDeclined }),
+                }
+            }, rejected).await
+        });
+
+        let mut client = Client::new(client);
+        client.call(ClientHello { nickname: "me".into(), programming_language: "Rust".into(), token: None }).await.unwrap();
+        client.lines.get_mut().write_all(b"Gibberish\n").await.unwrap();
+        match client.receive().await.unwrap() {
+            MultiplexedMessage { game_id: 0, action: Action::Error { code: ErrorCode::InvalidLine, .. } } => {},
+            other => panic!("unexpected {:?}", other),
+        }
+        let declined = client.call(Challenge { nickname: "you".into() }).await.unwrap();
+        assert_eq!(MultiplexedMessage { game_id: 1, action: Action::ChallengeDeclined }, declined);
+    }
+
+    #[tokio::test]
+    async fn closing_drops_open_replies() {
+        let (client, server) = tokio::io::duplex(4096);
+        let (answer, answered) = futures::channel::oneshot::channel::<ParsedLine>();
+        let mut answered = Some(answered);
+        let serving = tokio::spawn(async move {
+            let mut transport = lines(server);
+            let hello = handshake(&mut transport).await.unwrap();
+            serve(transport, hello, futures::stream::pending(), |request| {
+                let answered = match request {
+                    RequestNewGame => answered.take(),
+                    _ => None,
+                };
+                async move {
+                    match request {
+                        ClientHello { .. } => Ok(ServerHello { max_round_length: Duration::from_millis(500), session: None }),
+                        _ => answered.unwrap().await.map_err(io::Error::other),
+                    }
+                }
+            }, close_on_invalid_line).await
+        });
+
+        let mut client = Client::new(client);
+        client.call(ClientHello { nickname: "me".into(), programming_language: "Rust".into(), token: None }).await.unwrap();
+        client.lines.send(RequestNewGame).await.unwrap();
+        drop(client);
+        serving.await.unwrap().unwrap();
+        assert!(answer.is_canceled());
+    }
+
+    #[tokio::test]
+    async fn pushed_error_closes() {
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            let mut transport = lines(server);
+            let hello = handshake(&mut transport).await.unwrap();
+            let pushed = futures::stream::iter(vec![
+                Announcement { message: "bye".into() },
+                ParsedLine::Error { code: ErrorCode::Kicked, message: "kicked".into() },
+            ]);
+            serve(transport, hello, pushed, |_| async {
+                Ok(ServerHello { max_round_length: Duration::from_millis(500), session: None })
+            }, close_on_invalid_line).await
+        });
+
+        let mut client = Client::new(client);
+        client.call(ClientHello { nickname: "me".into(), programming_language: "Rust".into(), token: None }).await.unwrap();
+        assert_eq!(Announcement { message: "bye".into() }, client.receive().await.unwrap());
+        assert_eq!(ParsedLine::Error { code: ErrorCode::Kicked, message: "kicked".into() }, client.receive().await.unwrap());
+        assert!(client.receive().await.is_err());
     }
 }
@@ -0,0 +1,380 @@
+//! The rules engine, shared by the server and the bots
+
+use crate::*;
+
+/// One players move in a round, as it is reported in `ArenaResult`
+///
+/// On the wire it is encoded as `"nickname": Action` or `"nickname": Action -> "target"`
+#[derive(Clone,PartialEq)]
+pub struct PlayerMove {
+    /// Who did it
+    pub player: String,
+    /// What was done
+    pub action: RoundAction,
+    /// At whom it was aimed
+    pub target: Option<String>,
+}
+
+impl std::fmt::Debug for PlayerMove {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}: {:?}", self.player, self.action)?;
+        if let Some(ref target) = self.target {
+            write!(f, " -> {:?}", target)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct PlayerState {
+    /// Players primary key
+    pub nickname: String,
+    /// How much bullets are loaded?
+    pub ammo_bag: u64,
+    /// Is it still alive?
+    pub alive: bool,
+    /// Players with the same team id play together
+    pub team: Option<u64>,
+}
+
+impl PlayerState {
+    pub fn new(nickname: String) -> PlayerState {
+        PlayerState {
+            nickname,
+            ammo_bag: 0,
+            alive: true,
+            team: None,
+        }
+    }
+}
+
+/// Server perspective
+#[derive(Clone)]
+pub struct GameState {
+    pub game_id: u64,
+    /// Everyone in the arena, in the order they joined
+    pub players: Vec<PlayerState>,
+    /// May teammates hit each other?
+    pub friendly_fire: bool,
+}
+
+impl GameState {
+    pub fn new(game_id: u64, nicknames: Vec<String>) -> GameState {
+        GameState {
+            game_id,
+            players: nicknames.into_iter().map(PlayerState::new).collect(),
+            friendly_fire: true,
+        }
+    }
+
+    /// A team game, `players` are pairs of nickname and team id
+    pub fn with_teams(game_id: u64, players: Vec<(String, u64)>, friendly_fire: bool) -> GameState {
+        GameState {
+            game_id,
+            players: players.into_iter().map(|(nickname, team)| PlayerState { team: Some(team), ..PlayerState::new(nickname) }).collect(),
+            friendly_fire,
+        }
+    }
+
+    /// The team ids in order of appearance
+    pub fn teams(&self) -> Vec<u64> {
+        let mut teams = Vec::new();
+        for team in self.players.iter().filter_map(|p| p.team) {
+            if !teams.contains(&team) {
+                teams.push(team);
+            }
+        }
+        teams
+    }
+
+    /// Nicknames of everyone in `team`
+    pub fn team_members(&self, team: u64) -> Vec<String> {
+        self.players.iter().filter(|p| p.team == Some(team)).map(|p| p.nickname.clone()).collect()
+    }
+
+    pub fn player(&self, nickname: &str) -> Option<&PlayerState> {
+        self.players.iter().find(|p| p.nickname == nickname)
+    }
+
+    pub fn survivors(&self) -> Vec<&PlayerState> {
+        self.players.iter().filter(|p| p.alive).collect()
+    }
+
+    /// The game is over as soon as at most one player or team is left standing
+    pub fn is_over(&self) -> bool {
+        let survivors = self.survivors();
+        survivors.len() <= 1 || (survivors[0].team.is_some() && survivors.iter().all(|p| p.team == survivors[0].team))
+    }
+
+    /// The last one standing, if there is one
+    pub fn winner(&self) -> Option<&str> {
+        let survivors = self.survivors();
+        if survivors.len() == 1 {
+            Some(&survivors[0].nickname)
+        } else {
+            None
+        }
+    }
+
+    /// The last team standing, if this is a team game and it is over
+    pub fn winning_team(&self) -> Option<u64> {
+        if !self.is_over() {
+            return None
+        }
+        self.survivors().first().and_then(|p| p.team)
+    }
+
+    /// Did this player win the game, alone or with the team?
+    pub fn has_won(&self, nickname: &str) -> bool {
+        match self.winning_team() {
+            Some(team) => self.player(nickname).map(|p| p.team) == Some(Some(team)),
+            None => self.is_over() && self.winner() == Some(nickname),
+        }
+    }
+
+    /// Play one round and return what really happend, one move per surviving player
+    ///
+    /// Players without a move time out.
+    /// A shot without a target aims at the only opponent in a two player game.
+    /// Shooting with an empty magazine or at someone who is not alive results in a `Klick`,
+    /// so does shooting at a teammate without friendly fire.
+    /// Everyone hit during the round is eliminated at the end of it.
+    pub fn resolve_round(&mut self, moves: Vec<PlayerMove>) -> Vec<PlayerMove> {
+        let mut result: Vec<PlayerMove> = self.players.iter()
+            .filter(|p| p.alive)
+            .map(|p| {
+                match moves.iter().find(|m| m.player == p.nickname) {
+                    Some(m) => m.clone(),
+                    None => PlayerMove { player: p.nickname.clone(), action: Timeout, target: None },
+                }
+            })
+            .collect();
+
+        for m in result.iter_mut() {
+            if m.action != Shoot {
+                m.target = None;
+                continue
+            }
+            if m.target.is_none() {
+                let others: Vec<&PlayerState> = self.players.iter()
+                    .filter(|p| p.alive && p.nickname != m.player)
+                    .collect();
+                if others.len() == 1 {
+                    m.target = Some(others[0].nickname.clone());
+                }
+            }
+        }
+
+        let mut hit = Vec::new();
+        for m in result.iter_mut() {
+            match m.action {
+                Load => {
+                    self.player_mut(&m.player).ammo_bag += 1;
+                },
+                Shoot => {
+                    let valid_target = match m.target {
+                        Some(ref target) => *target != m.player && match self.player(target) {
+                            Some(t) => t.alive && (self.friendly_fire || t.team.is_none() || t.team != self.player(&m.player).and_then(|p| p.team)),
+                            None => false,
+                        },
+                        None => false,
+                    };
+                    let shooter = self.player_mut(&m.player);
+                    if shooter.ammo_bag == 0 || !valid_target {
+                        m.action = Klick;
+                    } else {
+                        shooter.ammo_bag -= 1;
+                        hit.push(m.target.clone().unwrap());
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        for target in hit {
+            let ducked = result.iter().any(|m| m.player == target && m.action == Duck);
+            if !ducked {
+                self.player_mut(&target).alive = false;
+            }
+        }
+
+        result
+    }
+
+    /// Follow a duel by the `RoundResult` of the server, whose actions are already resolved
+    pub fn follow_duel(&mut self, a: RoundAction, b: RoundAction) {
+        let moves = self.players.iter().zip(vec![a, b])
+            .map(|(p, action)| PlayerMove { player: p.nickname.clone(), action, target: None })
+            .collect();
+        self.resolve_round(moves);
+    }
+
+    fn player_mut(&mut self, nickname: &str) -> &mut PlayerState {
+        self.players.iter_mut().find(|p| p.nickname == nickname).expect("GameState::player_mut()")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    fn mv(player: &str, action: RoundAction, target: Option<&str>) -> PlayerMove {
+        PlayerMove {
+            player: player.into(),
+            action,
+            target: target.map(|t| t.into()),
+        }
+    }
+
+    fn three_player_game() -> GameState {
+        GameState::new(7, vec!["a".into(), "b".into(), "c".into()])
+    }
+
+    #[test]
+    fn load_fills_ammo_bag() {
+        let mut game = three_player_game();
+        game.resolve_round(vec![mv("a", Load, None)]);
+        assert_eq!(1, game.player("a").unwrap().ammo_bag);
+        assert_eq!(0, game.player("b").unwrap().ammo_bag);
+    }
+
+    #[test]
+    fn missing_moves_time_out() {
+        let mut game = three_player_game();
+        let result = game.resolve_round(vec![mv("b", Duck, None)]);
+        assert_eq!(vec![mv("a", Timeout, None), mv("b", Duck, None), mv("c", Timeout, None)], result);
+    }
+
+    #[test]
+    fn empty_magazine_klicks() {
+        let mut game = three_player_game();
+        let result = game.resolve_round(vec![mv("a", Shoot, Some("b"))]);
+        assert_eq!(mv("a", Klick, Some("b")), result[0]);
+        assert!(game.player("b").unwrap().alive);
+    }
+
+    #[test]
+    fn shot_eliminates_target() {
+        let mut game = three_player_game();
+        game.resolve_round(vec![mv("a", Load, None)]);
+        game.resolve_round(vec![mv("a", Shoot, Some("c")), mv("b", Load, None), mv("c", Load, None)]);
+        assert!(!game.player("c").unwrap().alive);
+        assert_eq!(0, game.player("a").unwrap().ammo_bag);
+        assert!(!game.is_over());
+    }
+
+    #[test]
+    fn duck_protects() {
+        let mut game = three_player_game();
+        game.resolve_round(vec![mv("a", Load, None)]);
+        game.resolve_round(vec![mv("a", Shoot, Some("b")), mv("b", Duck, None)]);
+        assert!(game.player("b").unwrap().alive);
+    }
+
+    #[test]
+    fn untargeted_shot_in_two_player_game() {
+        let mut game = GameState::new(1, vec!["a".into(), "b".into()]);
+        game.resolve_round(vec![mv("a", Load, None), mv("b", Load, None)]);
+        let result = game.resolve_round(vec![mv("a", Shoot, None), mv("b", Load, None)]);
+        assert_eq!(mv("a", Shoot, Some("b")), result[0]);
+        assert!(game.is_over());
+        assert_eq!(Some("a"), game.winner());
+    }
+
+    #[test]
+    fn untargeted_shot_in_arena_klicks() {
+        let mut game = three_player_game();
+        game.resolve_round(vec![mv("a", Load, None)]);
+        let result = game.resolve_round(vec![mv("a", Shoot, None)]);
+        assert_eq!(mv("a", Klick, None), result[0]);
+        assert_eq!(1, game.player("a").unwrap().ammo_bag);
+    }
+
+    #[test]
+    fn simultaneous_shots_leave_no_winner() {
+        let mut game = GameState::new(1, vec!["a".into(), "b".into()]);
+        game.resolve_round(vec![mv("a", Load, None), mv("b", Load, None)]);
+        game.resolve_round(vec![mv("a", Shoot, None), mv("b", Shoot, None)]);
+        assert!(game.is_over());
+        assert_eq!(None, game.winner());
+    }
+
+    #[test]
+    fn eliminated_players_do_not_move() {
+        let mut game = three_player_game();
+        game.resolve_round(vec![mv("a", Load, None)]);
+        game.resolve_round(vec![mv("a", Shoot, Some("c"))]);
+        let result = game.resolve_round(vec![mv("c", Load, None)]);
+        assert_eq!(2, result.len());
+        assert!(result.iter().all(|m| m.player != "c"));
+    }
+
+    fn team_game(friendly_fire: bool) -> GameState {
+        GameState::with_teams(9, vec![("a".into(), 1), ("b".into(), 1), ("c".into(), 2), ("d".into(), 2)], friendly_fire)
+    }
+
+    #[test]
+    fn teams_in_order() {
+        let game = team_game(false);
+        assert_eq!(vec![1, 2], game.teams());
+        assert_eq!(vec!["c".to_string(), "d".to_string()], game.team_members(2));
+    }
+
+    #[test]
+    fn no_friendly_fire() {
+        let mut game = team_game(false);
+        game.resolve_round(vec![mv("a", Load, None)]);
+        let result = game.resolve_round(vec![mv("a", Shoot, Some("b"))]);
+        assert_eq!(mv("a", Klick, Some("b")), result[0]);
+        assert!(game.player("b").unwrap().alive);
+    }
+
+    #[test]
+    fn friendly_fire() {
+        let mut game = team_game(true);
+        game.resolve_round(vec![mv("a", Load, None)]);
+        game.resolve_round(vec![mv("a", Shoot, Some("b"))]);
+        assert!(!game.player("b").unwrap().alive);
+        assert!(!game.is_over());
+    }
+
+    #[test]
+    fn last_team_standing_wins() {
+        let mut game = team_game(false);
+        game.resolve_round(vec![mv("a", Load, None), mv("b", Load, None)]);
+        game.resolve_round(vec![mv("a", Shoot, Some("c")), mv("b", Shoot, Some("d"))]);
+        assert!(game.is_over());
+        assert_eq!(Some(1), game.winning_team());
+        assert!(game.has_won("a"));
+        assert!(game.has_won("b"));
+        assert!(!game.has_won("c"));
+    }
+
+    #[test]
+    fn dead_teammate_wins_too() {
+        let mut game = team_game(true);
+        game.resolve_round(vec![mv("a", Load, None), mv("c", Load, None), mv("d", Load, None)]);
+        game.resolve_round(vec![mv("c", Shoot, Some("b")), mv("a", Load, None), mv("d", Load, None)]);
+        game.resolve_round(vec![mv("a", Shoot, Some("c")), mv("d", Duck, None)]);
+        game.resolve_round(vec![mv("a", Shoot, Some("d"))]);
+        assert!(game.has_won("b"));
+        assert!(!game.has_won("d"));
+    }
+
+    #[test]
+    fn encode_player_move() {
+        assert_eq!("\"a\": Shoot -> \"b\"", format!("{:?}", mv("a", Shoot, Some("b"))));
+        assert_eq!("\"a\": Duck", format!("{:?}", mv("a", Duck, None)));
+    }
+
+    #[test]
+    fn follow_duel_from_results() {
+        let mut game = GameState::new(1, vec!["a".into(), "b".into()]);
+        game.follow_duel(Load, Klick);
+        game.follow_duel(Shoot, Duck);
+        assert!(!game.is_over());
+        game.follow_duel(Load, Timeout);
+        game.follow_duel(Shoot, Load);
+        assert_eq!(Some("a"), game.winner());
+    }
+}
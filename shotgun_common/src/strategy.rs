@@ -0,0 +1,108 @@
+//! Simple strategies playing by the rules engine
+
+use crate::*;
+
+/// Parse like this:
+///
+/// ```
+/// # use shotgun_common::strategy::Strategy;
+/// let strategy: Strategy = "coward".parse().unwrap();
+/// ```
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Strategy {
+    /// Ducks whenever an opponent could shoot
+    Coward,
+    /// Loads one bullet and fires it right away
+    Gunslinger,
+}
+use self::Strategy::*;
+
+impl Strategy {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Coward => "coward",
+            Gunslinger => "gunslinger",
+        }
+    }
+
+    /// Decide on the move of `me` for the next round, looking at the state before it
+    pub fn next_move(&self, game: &GameState, me: &str) -> PlayerMove {
+        let ammo = game.player(me).map(|p| p.ammo_bag).unwrap_or(0);
+        let opponents: Vec<&PlayerState> = game.survivors().into_iter()
+            .filter(|p| p.nickname != me && (p.team.is_none() || p.team != game.player(me).and_then(|p| p.team)))
+            .collect();
+        // The most dangerous opponent first
+        let target = opponents.iter().rev().max_by_key(|p| p.ammo_bag).map(|p| p.nickname.clone());
+        let threatened = opponents.iter().any(|p| p.ammo_bag > 0);
+
+        let action = match *self {
+            Coward if threatened => Duck,
+            _ if ammo > 0 && target.is_some() => Shoot,
+            _ => Load,
+        };
+        PlayerMove {
+            player: me.into(),
+            target: if action == Shoot { target } else { None },
+            action,
+        }
+    }
+}
+
+impl std::str::FromStr for Strategy {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "coward"     => Ok(Coward    ),
+            "gunslinger" => Ok(Gunslinger),
+            text => Err(InvalidAction(format!("invalid Strategy: {:?}", text))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn game() -> GameState {
+        GameState::new(1, vec!["me".into(), "you".into(), "them".into()])
+    }
+
+    #[test]
+    fn parse_strategies() {
+        assert_eq!(Ok(Coward), "coward".parse());
+        assert_eq!(Ok(Gunslinger), "gunslinger".parse());
+        assert!("hero".parse::<Strategy>().is_err());
+    }
+
+    #[test]
+    fn loads_first() {
+        assert_eq!(Load, Coward.next_move(&game(), "me").action);
+        assert_eq!(Load, Gunslinger.next_move(&game(), "me").action);
+    }
+
+    #[test]
+    fn coward_ducks_when_threatened() {
+        let mut game = game();
+        game.players[2].ammo_bag = 1;
+        game.players[0].ammo_bag = 1;
+        assert_eq!(Duck, Coward.next_move(&game, "me").action);
+    }
+
+    #[test]
+    fn gunslinger_shoots_most_dangerous() {
+        let mut game = game();
+        game.players[0].ammo_bag = 1;
+        game.players[2].ammo_bag = 2;
+        let mv = Gunslinger.next_move(&game, "me");
+        assert_eq!(Shoot, mv.action);
+        assert_eq!(Some("them".to_string()), mv.target);
+    }
+
+    #[test]
+    fn spares_teammates() {
+        let mut game = GameState::with_teams(1, vec![("me".into(), 1), ("mate".into(), 1), ("you".into(), 2)], false);
+        game.players[0].ammo_bag = 1;
+        game.players[1].ammo_bag = 3;
+        assert_eq!(Some("you".to_string()), Gunslinger.next_move(&game, "me").target);
+    }
+}
@@ -0,0 +1,131 @@
+//! TLS around any connection, so the `LineCodec` can be spoken encrypted
+//!
+//! `accept` or `connect` the stream, everything after the TLS handshake is unchanged.
+
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::RootCertStore;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::pki_types::pem::PemObject;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{client, server, TlsAcceptor, TlsConnector};
+
+pub use rustls::{ClientConfig, ServerConfig};
+
+/// The server side of a TLS handshake on an accepted connection
+pub async fn accept<T>(config: Arc<ServerConfig>, io: T) -> io::Result<server::TlsStream<T>>
+    where T: AsyncRead + AsyncWrite + Unpin
+{
+    TlsAcceptor::from(config).accept(io).await
+}
+
+/// The certificate of the server must be valid for `server_name`, a host name or an IP address
+pub async fn connect<T>(config: Arc<ClientConfig>, server_name: &str, io: T) -> io::Result<client::TlsStream<T>>
+    where T: AsyncRead + AsyncWrite + Unpin
+{
+    let server_name = ServerName::try_from(server_name.to_string())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    TlsConnector::from(config).connect(server_name, io).await
+}
+
+/// Server configuration from a PEM certificate chain and a PEM private key
+pub fn server_config(cert: &Path, key: &Path) -> io::Result<Arc<ServerConfig>> {
+    let certs = load_certs(cert)?;
+    let key = PrivateKeyDer::from_pem_reader(BufReader::new(File::open(key)?))
+        .map_err(|e| invalid_data(key, e))?;
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    Ok(Arc::new(config))
+}
+
+/// Client configuration trusting the certificates in `ca`, or the well known root CAs if there is none
+pub fn client_config(ca: Option<&Path>) -> io::Result<Arc<ClientConfig>> {
+    let roots = match ca {
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca)? {
+                roots.add(cert).map_err(|e| invalid_data(ca, e))?;
+            }
+            roots
+        },
+        None => RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() },
+    };
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_reader_iter(BufReader::new(File::open(path)?))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| invalid_data(path, e))?;
+    if certs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("no certificate in {:?}", path)))
+    }
+    Ok(certs)
+}
+
+fn invalid_data<E: std::fmt::Display>(path: &Path, e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{:?}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    fn testdata(file: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata").join(file)
+    }
+
+    async fn echo(config: Arc<ClientConfig>) -> io::Result<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server_config = server_config(&testdata("cert.pem"), &testdata("key.pem")).unwrap();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            if let Ok(stream) = accept(server_config, socket).await {
+                let mut stream = tokio::io::BufReader::new(stream);
+                let mut line = String::new();
+                if stream.read_line(&mut line).await.is_ok() {
+                    let _ = stream.write_all(line.as_bytes()).await;
+                    let _ = stream.flush().await;
+                }
+            }
+        });
+
+        let stream = connect(config, "localhost", TcpStream::connect(addr).await?).await?;
+        let mut stream = tokio::io::BufReader::new(stream);
+        stream.write_all(b"RequestNewGame\n").await?;
+        stream.flush().await?;
+        let mut line = String::new();
+        stream.read_line(&mut line).await?;
+        Ok(line)
+    }
+
+    #[tokio::test]
+    async fn self_signed_round_trip() {
+        let config = client_config(Some(&testdata("cert.pem"))).unwrap();
+        assert_eq!("RequestNewGame\n", echo(config).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn untrusted_certificate() {
+        assert!(echo(client_config(None).unwrap()).await.is_err());
+    }
+
+    #[test]
+    fn missing_key() {
+        assert!(server_config(&testdata("cert.pem"), &testdata("missing.pem")).is_err());
+    }
+}
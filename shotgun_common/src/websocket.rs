@@ -0,0 +1,425 @@
+//! WebSocket transport for browsers, each text frame carries one `ParsedLine`
+//!
+//! After the `upgrade` the connection behaves exactly like a line based one,
+//! starting with the `ClientHello`.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use bytes::{BytesMut, BufMut};
+use futures::{ready, Sink, SinkExt, Stream, StreamExt};
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+use crate::*;
+use crate::http::{HttpCodec, Request, Response};
+
+/// Appended to the `Sec-WebSocket-Key` of the client, see RFC 6455
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Longer messages are refused, a `ParsedLine` is much shorter
+const MAX_PAYLOAD: u64 = 64 * 1024;
+
+/// Close codes of RFC 6455
+const PROTOCOL_ERROR: u16 = 1002;
+const MESSAGE_TOO_BIG: u16 = 1009;
+
+pub const CONTINUATION: u8 = 0x0;
+pub const TEXT: u8 = 0x1;
+pub const BINARY: u8 = 0x2;
+pub const CLOSE: u8 = 0x8;
+pub const PING: u8 = 0x9;
+pub const PONG: u8 = 0xA;
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn text(text: &str) -> Frame {
+        Frame { fin: true, opcode: TEXT, payload: text.as_bytes().to_vec() }
+    }
+}
+
+/// Decodes masked client frames and encodes unmasked server frames
+pub struct FrameCodec;
+
+impl Decoder for FrameCodec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Frame>> {
+        if buf.len() < 2 {
+            return Ok(None)
+        }
+        let fin = buf[0] & 0x80 != 0;
+        let opcode = buf[0] & 0x0F;
+        if buf[1] & 0x80 == 0 {
+            return Err(invalid("client frames must be masked"))
+        }
+        let (len, offset) = match buf[1] & 0x7F {
+            126 if buf.len() >= 4 => (u64::from(buf[2]) << 8 | u64::from(buf[3]), 4),
+            127 if buf.len() >= 10 => (buf[2..10].iter().fold(0, |len, &b| len << 8 | u64::from(b)), 10),
+            126 | 127 => return Ok(None),
+            len => (u64::from(len), 2),
+        };
+        if len > MAX_PAYLOAD {
+            return Err(invalid("frame too long"))
+        }
+        let len = len as usize;
+        if buf.len() < offset + 4 + len {
+            return Ok(None)
+        }
+
+        let frame = buf.split_to(offset + 4 + len);
+        let mask = &frame[offset..offset + 4];
+        let payload = frame[offset + 4..].iter().enumerate()
+            .map(|(i, b)| b ^ mask[i % 4])
+            .collect();
+        Ok(Some(Frame { fin, opcode, payload }))
+    }
+}
+
+impl Encoder<Frame> for FrameCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, frame: Frame, buf: &mut BytesMut) -> io::Result<()> {
+        let len = frame.payload.len();
+        buf.reserve(len + 10);
+        buf.put_u8(if frame.fin { 0x80 } else { 0 } | frame.opcode);
+        if len < 126 {
+            buf.put_u8(len as u8);
+        } else if len <= 0xFFFF {
+            buf.put_u8(126);
+            buf.put_slice(&[(len >> 8) as u8, len as u8]);
+        } else {
+            buf.put_u8(127);
+            for shift in (0..8).rev() {
+                buf.put_u8((len as u64 >> (8 * shift)) as u8);
+            }
+        }
+        buf.put_slice(&frame.payload);
+        Ok(())
+    }
+}
+
+/// Turns frames into lines, answers pings and closes
+pub struct WebSocketTransport<T> {
+    inner: Framed<T, FrameCodec>,
+    /// Fragments of the current message, once its first frame arrived
+    message: Option<Vec<u8>>,
+    /// A control frame was answered but not flushed yet
+    unflushed: bool,
+    /// The close frame went out, nothing is sent or read after it
+    closed: bool,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> WebSocketTransport<T> {
+    pub fn new(inner: Framed<T, FrameCodec>) -> WebSocketTransport<T> {
+        WebSocketTransport { inner, message: None, unflushed: false, closed: false }
+    }
+
+    /// Close the connection with the status `code`, the error tells why
+    fn fail(&mut self, cx: &mut Context, code: u16, reason: &str) -> io::Error {
+        self.closed = true;
+        let _ = self.reply(cx, Frame { fin: true, opcode: CLOSE, payload: code.to_be_bytes().to_vec() });
+        invalid(reason)
+    }
+
+    /// Control frames are answered on a best effort basis
+    fn reply(&mut self, cx: &mut Context, frame: Frame) -> io::Result<()> {
+        Pin::new(&mut self.inner).start_send(frame)?;
+        self.flush_reply(cx)
+    }
+
+    fn flush_reply(&mut self, cx: &mut Context) -> io::Result<()> {
+        self.unflushed = Pin::new(&mut self.inner).poll_flush(cx)?.is_pending();
+        Ok(())
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Stream for WebSocketTransport<T> {
+    type Item = io::Result<ParsedLine>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<io::Result<ParsedLine>>> {
+        let this = &mut *self;
+        if this.unflushed {
+            this.flush_reply(cx)?;
+        }
+        if this.closed {
+            return Poll::Ready(None)
+        }
+        loop {
+            let frame = match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(frame) => frame?,
+                None => return Poll::Ready(None),
+            };
+            match frame.opcode {
+                TEXT | CONTINUATION => {
+                    let mut message = match (frame.opcode, this.message.take()) {
+                        (TEXT, None) => vec![],
+                        (CONTINUATION, Some(message)) => message,
+                        _ => return Poll::Ready(Some(Err(this.fail(cx, PROTOCOL_ERROR, "fragments out of order")))),
+                    };
+                    if (message.len() + frame.payload.len()) as u64 > MAX_PAYLOAD {
+                        return Poll::Ready(Some(Err(this.fail(cx, MESSAGE_TOO_BIG, "message too long"))))
+                    }
+                    message.extend(frame.payload);
+                    if !frame.fin {
+                        this.message = Some(message);
+                        continue
+                    }
+                    let text = String::from_utf8(message)
+                        .map_err(|_| io::Error::other("invalid UTF-8"))?;
+                    let line = text.trim_end_matches('\n').parse()
+                        .map_err(|e: ParseError| io::Error::other(e))?;
+                    return Poll::Ready(Some(Ok(line)))
+                },
+                PING => this.reply(cx, Frame { fin: true, opcode: PONG, payload: frame.payload })?,
+                PONG => {},
+                CLOSE => {
+                    this.closed = true;
+                    this.reply(cx, Frame { fin: true, opcode: CLOSE, payload: vec![] })?;
+                    return Poll::Ready(None)
+                },
+                _ => return Poll::Ready(Some(Err(invalid("only text frames are supported")))),
+            }
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Sink<ParsedLine> for WebSocketTransport<T> {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: ParsedLine) -> io::Result<()> {
+        if self.closed {
+            return Ok(())
+        }
+        Pin::new(&mut self.inner).start_send(Frame::text(&item.serialize()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// The server side of the HTTP upgrade, afterwards the client sends its `ClientHello`
+pub async fn upgrade<T: AsyncRead + AsyncWrite + Unpin>(io: T) -> io::Result<WebSocketTransport<T>> {
+    let mut http = Framed::new(io, HttpCodec);
+    let request = http.next().await.transpose()?;
+    let accept = match request.as_ref().filter(|r| is_upgrade(r)).and_then(|r| r.header("Sec-WebSocket-Key")) {
+        Some(key) => accept_key(key),
+        None => {
+            let _ = http.send(Response::new(400).header("Sec-WebSocket-Version", "13")).await;
+            return Err(invalid("invalid WebSocket upgrade"))
+        }
+    };
+    http.send(Response::new(101)
+        .header("Upgrade", "websocket")
+        .header("Connection", "Upgrade")
+        .header("Sec-WebSocket-Accept", &accept)).await?;
+    // Frames the client sent right after the request are kept
+    Ok(WebSocketTransport::new(http.map_codec(|_| FrameCodec)))
+}
+
+/// A `GET` asking for version 13 of the WebSocket protocol
+fn is_upgrade(request: &Request) -> bool {
+    request.method == "GET"
+        && request.header("Upgrade").is_some_and(|u| u.eq_ignore_ascii_case("websocket"))
+        && request.header("Sec-WebSocket-Version") == Some("13")
+}
+
+/// The `Sec-WebSocket-Accept` answering the `Sec-WebSocket-Key` of the client
+pub fn accept_key(key: &str) -> String {
+    base64(&Sha1::digest(format!("{}{}", key, ACCEPT_GUID).as_bytes()))
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | u32::from(b) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use crate::networking::handshake;
+
+    fn masked(opcode: u8, payload: &[u8]) -> BytesMut {
+        fragment(true, opcode, payload)
+    }
+
+    fn fragment(fin: bool, opcode: u8, payload: &[u8]) -> BytesMut {
+        let mask = [1, 2, 3, 4];
+        let mut buf = BytesMut::new();
+        buf.put_u8(if fin { 0x80 } else { 0 } | opcode);
+        if payload.len() < 126 {
+            buf.put_u8(0x80 | payload.len() as u8);
+        } else {
+            buf.put_u8(0x80 | 126);
+            buf.put_u16(payload.len() as u16);
+        }
+        buf.extend_from_slice(&mask);
+        buf.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        buf
+    }
+
+    /// A connection on which the client already sent everything in `input`, the client end comes first
+    async fn replay(input: &[u8]) -> (DuplexStream, DuplexStream) {
+        let (mut client, server) = tokio::io::duplex(256 * 1024);
+        client.write_all(input).await.unwrap();
+        client.shutdown().await.unwrap();
+        (client, server)
+    }
+
+    const UPGRADE: &[u8] = b"GET / HTTP/1.1\r\nUpgrade: websocket\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+
+    /// What the server sent once the transport is gone
+    async fn sent(mut client: DuplexStream, transport: WebSocketTransport<DuplexStream>) -> Vec<u8> {
+        drop(transport);
+        let mut sent = vec![];
+        client.read_to_end(&mut sent).await.unwrap();
+        sent
+    }
+
+    #[tokio::test]
+    async fn upgrade_and_handshake() {
+        let mut input = UPGRADE.to_vec();
+        input.extend(masked(TEXT, b"Nickname: >me<>js").iter());
+
+        let (_client, server) = replay(&input).await;
+        let mut transport = upgrade(server).await.unwrap();
+        let hello = handshake(&mut transport).await.unwrap();
+        assert_eq!(ClientHello { nickname: "me".into(), programming_language: "js".into(), token: None }, hello);
+    }
+
+    #[tokio::test]
+    async fn plain_line_is_no_upgrade() {
+        let (_client, server) = replay(b"Nickname: >me<>rust\n").await;
+        assert!(upgrade(server).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn upgrade_needs_get_and_version_13() {
+        let requests = [
+            "POST / HTTP/1.1\r\nUpgrade: websocket\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Key: x\r\n\r\n",
+            "GET / HTTP/1.1\r\nUpgrade: h2c\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Key: x\r\n\r\n",
+            "GET / HTTP/1.1\r\nUpgrade: websocket\r\nSec-WebSocket-Version: 8\r\nSec-WebSocket-Key: x\r\n\r\n",
+        ];
+        for request in requests {
+            let (mut client, server) = replay(request.as_bytes()).await;
+            assert!(upgrade(server).await.is_err());
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 400 "), "{}", response);
+        }
+    }
+
+    #[tokio::test]
+    async fn fragments_make_one_line() {
+        let mut input = UPGRADE.to_vec();
+        input.extend(fragment(false, TEXT, b"Nickname: >").iter());
+        input.extend(fragment(false, CONTINUATION, b"me<").iter());
+        input.extend(fragment(true, CONTINUATION, b">js").iter());
+
+        let (_client, server) = replay(&input).await;
+        let mut transport = upgrade(server).await.unwrap();
+        assert_eq!(ClientHello { nickname: "me".into(), programming_language: "js".into(), token: None }, transport.next().await.unwrap().unwrap());
+    }
+
+    #[tokio::test]
+    async fn continuation_without_start_closes() {
+        let mut input = UPGRADE.to_vec();
+        input.extend(masked(CONTINUATION, b"RequestNewGame").iter());
+
+        let (client, server) = replay(&input).await;
+        let mut transport = upgrade(server).await.unwrap();
+        assert!(transport.next().await.unwrap().is_err());
+        assert!(transport.next().await.is_none());
+        assert!(sent(client, transport).await.ends_with(&[0x80 | CLOSE, 2, 0x03, 0xEA]));
+    }
+
+    #[tokio::test]
+    async fn long_message_closes() {
+        let mut input = UPGRADE.to_vec();
+        input.extend(fragment(false, TEXT, &[b'x'; 40_000]).iter());
+        input.extend(fragment(true, CONTINUATION, &[b'x'; 40_000]).iter());
+
+        let (client, server) = replay(&input).await;
+        let mut transport = upgrade(server).await.unwrap();
+        assert!(transport.next().await.unwrap().is_err());
+        assert!(sent(client, transport).await.ends_with(&[0x80 | CLOSE, 2, 0x03, 0xF1]));
+    }
+
+    #[test]
+    fn accept_key_from_rfc() {
+        assert_eq!("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=", accept_key("dGhlIHNhbXBsZSBub25jZQ=="));
+    }
+
+    #[test]
+    fn encode_base64() {
+        assert_eq!("", base64(b""));
+        assert_eq!("Zg==", base64(b"f"));
+        assert_eq!("Zm8=", base64(b"fo"));
+        assert_eq!("Zm9v", base64(b"foo"));
+    }
+
+    #[test]
+    fn decode_masked_frame() {
+        let mut buf = masked(TEXT, b"RequestNewGame");
+        assert_eq!(Some(Frame::text("RequestNewGame")), FrameCodec.decode(&mut buf).unwrap());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn decode_partial_frame() {
+        let mut buf = masked(TEXT, b"RequestNewGame");
+        buf.truncate(8);
+        assert_eq!(None, FrameCodec.decode(&mut buf).unwrap());
+    }
+
+    #[test]
+    fn unmasked_frame_is_refused() {
+        let mut buf = BytesMut::from(&[0x81, 0x01, b'x'][..]);
+        assert!(FrameCodec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn encode_frames() {
+        let mut buf = BytesMut::new();
+        FrameCodec.encode(Frame::text("ok"), &mut buf).unwrap();
+        assert_eq!(&[0x81, 0x02, b'o', b'k'][..], &buf[..]);
+
+        let mut buf = BytesMut::new();
+        FrameCodec.encode(Frame::text(&"x".repeat(300)), &mut buf).unwrap();
+        assert_eq!(&[0x81, 126, 0x01, 0x2C][..], &buf[..4]);
+        assert_eq!(304, buf.len());
+    }
+}
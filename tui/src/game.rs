@@ -0,0 +1,173 @@
+//! Games as seen by a spectator, as recorded in the replays of the HTTP API
+
+use rustc_serialize::json::Json;
+
+use shotgun_common::*;
+
+/// One entry of `/games/live` or `/games/finished`
+#[derive(Debug,Clone,PartialEq)]
+pub struct Summary {
+    pub game_id: u64,
+    pub live: bool,
+    pub match_id: Option<u64>,
+    /// Nickname and team, in the order they joined
+    pub players: Vec<(String, Option<u64>)>,
+    /// Who is not alive anymore, forfeits between rounds only show here
+    pub dead: Vec<String>,
+}
+
+impl Summary {
+    pub fn from_json(json: &Json) -> Result<Summary, String> {
+        let players = json.find("players").and_then(|p| p.as_array()).ok_or("missing players")?;
+        Ok(Summary {
+            game_id: json.find("game_id").and_then(|id| id.as_u64()).ok_or("missing game_id")?,
+            live: json.find("live").and_then(|l| l.as_boolean()).unwrap_or(false),
+            match_id: json.find("match_id").and_then(|id| id.as_u64()),
+            players: players.iter()
+                .map(|p| {
+                    let nickname = p.find("nickname").and_then(|n| n.as_string()).ok_or("missing nickname")?;
+                    Ok((nickname.to_string(), p.find("team").and_then(|t| t.as_u64())))
+                })
+                .collect::<Result<_, String>>()?,
+            dead: players.iter()
+                .filter(|p| p.find("alive").and_then(|a| a.as_boolean()) == Some(false))
+                .filter_map(|p| p.find("nickname").and_then(|n| n.as_string()).map(|n| n.to_string()))
+                .collect(),
+        })
+    }
+
+    /// A list of summaries, like `/games/live`
+    pub fn list(json: &Json) -> Result<Vec<Summary>, String> {
+        json.as_array().ok_or("not a list")?.iter().map(Summary::from_json).collect()
+    }
+
+    pub fn title(&self) -> String {
+        let names: Vec<&str> = self.players.iter().map(|p| p.0.as_str()).collect();
+        format!("#{} {}", self.game_id, names.join(" vs "))
+    }
+}
+
+/// A game as of its last round
+pub struct GameView {
+    pub summary: Summary,
+    pub state: GameState,
+    /// The moves of every round
+    pub history: Vec<Vec<PlayerMove>>,
+}
+
+impl GameView {
+    /// Follow the rounds of `/games/{id}/replay`
+    pub fn replay(summary: Summary, replay: &Json) -> Result<GameView, String> {
+        let mut state = if summary.players.iter().any(|p| p.1.is_some()) {
+            let players = summary.players.iter().map(|p| (p.0.clone(), p.1.unwrap_or(0))).collect();
+            GameState::with_teams(summary.game_id, players, true)
+        } else {
+            GameState::new(summary.game_id, summary.players.iter().map(|p| p.0.clone()).collect())
+        };
+
+        // Every round carries everyone after it, forfeits and disconnects included
+        let rounds = replay.find("rounds").and_then(|r| r.as_array()).ok_or("missing rounds")?;
+        let mut history = Vec::new();
+        for round in rounds {
+            let moves = round.find("moves").and_then(|m| m.as_array()).ok_or("missing moves")?;
+            history.push(moves.iter().map(player_move).collect::<Result<Vec<_>, _>>()?);
+            let players = round.find("players").and_then(|p| p.as_array()).ok_or("missing players")?;
+            state.players = players.iter().map(player_state).collect::<Result<_, _>>()?;
+        }
+        for player in state.players.iter_mut().filter(|p| summary.dead.contains(&p.nickname)) {
+            player.alive = false;
+        }
+
+        Ok(GameView { summary, state, history })
+    }
+
+    /// `None` while the game is running
+    pub fn result(&self) -> Option<String> {
+        if !self.state.is_over() {
+            return None
+        }
+        Some(match (self.state.winning_team(), self.state.winner()) {
+            (Some(team), _) => format!("team {} won", team),
+            (None, Some(winner)) => format!("{} won", winner),
+            (None, None) => "draw".to_string(),
+        })
+    }
+}
+
+fn player_move(json: &Json) -> Result<PlayerMove, String> {
+    let field = |name: &str| json.find(name).and_then(|f| f.as_string()).map(|f| f.to_string());
+    Ok(PlayerMove {
+        player: field("player").ok_or("missing player")?,
+        action: field("action").ok_or("missing action")?.parse().map_err(|e| format!("{:?}", e))?,
+        target: field("target"),
+    })
+}
+
+fn player_state(json: &Json) -> Result<PlayerState, String> {
+    Ok(PlayerState {
+        nickname: json.find("nickname").and_then(|n| n.as_string()).ok_or("missing nickname")?.to_string(),
+        ammo_bag: json.find("ammo_bag").and_then(|a| a.as_u64()).ok_or("missing ammo_bag")?,
+        alive: json.find("alive").and_then(|a| a.as_boolean()).ok_or("missing alive")?,
+        team: json.find("team").and_then(|t| t.as_u64()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shotgun_common::RoundAction::*;
+
+    fn summary() -> Summary {
+        let json = Json::from_str(r#"{"game_id":3,"live":false,"match_id":null,"rounds":2,"winner":"a",
+            "players":[{"nickname":"a","ammo_bag":0,"alive":true,"team":null},{"nickname":"b","ammo_bag":0,"alive":false,"team":null}]}"#).unwrap();
+        Summary::from_json(&json).unwrap()
+    }
+
+    #[test]
+    fn parse_summary() {
+        let summary = summary();
+        assert_eq!(3, summary.game_id);
+        assert_eq!(vec![("a".to_string(), None), ("b".to_string(), None)], summary.players);
+        assert_eq!("#3 a vs b", summary.title());
+    }
+
+    fn live() -> Summary {
+        Summary { live: true, dead: vec![], ..summary() }
+    }
+
+    #[test]
+    fn replay_rounds() {
+        let replay = Json::from_str(r#"{"game_id":3,"players":["a","b"],"rounds":[
+            {"moves":[{"player":"a","action":"Load","target":null},{"player":"b","action":"Load","target":null}],
+             "players":[{"nickname":"a","ammo_bag":1,"alive":true,"team":null},{"nickname":"b","ammo_bag":1,"alive":true,"team":null}]},
+            {"moves":[{"player":"a","action":"Shoot","target":"b"},{"player":"b","action":"Shoot","target":"a"}],
+             "players":[{"nickname":"a","ammo_bag":0,"alive":false,"team":null},{"nickname":"b","ammo_bag":0,"alive":false,"team":null}]}]}"#).unwrap();
+        let view = GameView::replay(summary(), &replay).unwrap();
+        assert_eq!(2, view.history.len());
+        assert_eq!(Shoot, view.history[1][0].action);
+        assert_eq!(Some(0), view.state.player("a").map(|p| p.ammo_bag));
+        assert_eq!(Some("draw".to_string()), view.result());
+    }
+
+    #[test]
+    fn running_game_has_no_result() {
+        let replay = Json::from_str(r#"{"rounds":[{"moves":[{"player":"a","action":"Load","target":null},{"player":"b","action":"Timeout","target":null}],
+            "players":[{"nickname":"a","ammo_bag":1,"alive":true,"team":null},{"nickname":"b","ammo_bag":0,"alive":true,"team":null}]}]}"#).unwrap();
+        let view = GameView::replay(live(), &replay).unwrap();
+        assert_eq!(Some(1), view.state.player("a").map(|p| p.ammo_bag));
+        assert_eq!(Timeout, view.history[0][1].action);
+        assert_eq!(None, view.result());
+    }
+
+    #[test]
+    fn forfeits_count() {
+        // b disconnected during the round, the moves alone would not kill anybody
+        let replay = Json::from_str(r#"{"rounds":[{"moves":[{"player":"a","action":"Load","target":null},{"player":"b","action":"Timeout","target":null}],
+            "players":[{"nickname":"a","ammo_bag":1,"alive":true,"team":null},{"nickname":"b","ammo_bag":0,"alive":false,"team":null}]}]}"#).unwrap();
+        assert_eq!(Some("a won".to_string()), GameView::replay(live(), &replay).unwrap().result());
+        // b left between rounds, only the summary knows
+        let replay = Json::from_str(r#"{"rounds":[]}"#).unwrap();
+        assert_eq!(None, GameView::replay(live(), &replay).unwrap().result());
+        assert_eq!(Some("a won".to_string()), GameView::replay(summary(), &replay).unwrap().result());
+    }
+}
@@ -0,0 +1,251 @@
+/// Follow the games of a gameserver in the terminal
+extern crate rustc_serialize;
+extern crate docopt;
+extern crate termion;
+extern crate shotgun_common;
+
+mod game;
+
+use std::io;
+use std::io::prelude::*;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rustc_serialize::json::Json;
+use termion::{clear, color, cursor, style};
+use termion::event::Key;
+use termion::input::TermRead;
+use termion::raw::IntoRawMode;
+use termion::screen::AlternateScreen;
+
+use game::{GameView, Summary};
+
+#[derive(Debug,RustcDecodable)]
+struct Args {
+    flag_port  : u16,
+    flag_target: String,
+    flag_refresh: u64,
+}
+
+static USAGE: &str = "
+Shotgun Spectator
+
+Usage:
+  shotgun_tui [--target=<HOST>] [--port=<PORT>] [--refresh=<MS>]
+  shotgun_tui (-h | --help)
+
+Options:
+    --port=<PORT>      The --http-port of the gameserver [default: 6081]
+    --target=<HOST>    The host name or IP address of the gameserver [default: ::1]
+    --refresh=<MS>     How often to ask the gameserver for news [default: 500]
+
+Keys:
+    left/right, h/l    Previous/next game
+    f                  Show finished games instead of live ones
+    q                  Quit
+";
+
+/// Everything on screen
+struct Spectator {
+    /// Host name or IP address of the gameserver
+    target: String,
+    port: u16,
+    show_finished: bool,
+    games: Vec<Summary>,
+    /// Game id of the selected game, it stays selected when it ends
+    selected: Option<u64>,
+    view: Option<GameView>,
+    error: Option<String>,
+}
+
+impl Spectator {
+    fn refresh(&mut self) {
+        let path = if self.show_finished { "/games/finished" } else { "/games/live" };
+        let result = get_json(&self.target, self.port, path).and_then(|json| Summary::list(&json));
+        match result {
+            Ok(games) => {
+                self.games = games;
+                self.error = None;
+            },
+            Err(e) => self.error = Some(e),
+        }
+
+        if self.selected.is_none() {
+            self.selected = self.games.first().map(|g| g.game_id);
+        }
+        let game_id = match self.selected {
+            Some(game_id) => game_id,
+            None => {
+                self.view = None;
+                return
+            },
+        };
+        // A game that just ended is not live anymore, but still there to look at
+        let summary = match self.games.iter().find(|g| g.game_id == game_id) {
+            Some(summary) => Ok(summary.clone()),
+            None => get_json(&self.target, self.port, &format!("/games/{}", game_id)).and_then(|json| Summary::from_json(&json)),
+        };
+        let view = summary.and_then(|summary| {
+            let replay = get_json(&self.target, self.port, &format!("/games/{}/replay", game_id))?;
+            GameView::replay(summary, &replay)
+        });
+        match view {
+            Ok(view) => self.view = Some(view),
+            Err(e) => self.error = Some(e),
+        }
+    }
+
+    /// Move the selection by `step` games
+    fn select(&mut self, step: isize) {
+        if self.games.is_empty() {
+            return
+        }
+        let current = self.games.iter().position(|g| Some(g.game_id) == self.selected).unwrap_or(0) as isize;
+        let next = (current + step).rem_euclid(self.games.len() as isize) as usize;
+        self.selected = Some(self.games[next].game_id);
+    }
+
+    fn render<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        write!(out, "{}{}", clear::All, cursor::Goto(1, 1))?;
+        let kind = if self.show_finished { "finished" } else { "live" };
+        write!(out, "{}Shotgun spectator{} {} - {} {} games\r\n", style::Bold, style::Reset, authority(&self.target, self.port), self.games.len(), kind)?;
+        if let Some(ref error) = self.error {
+            write!(out, "{}{}{}\r\n", color::Fg(color::Red), error, color::Fg(color::Reset))?;
+        }
+
+        for game in &self.games {
+            if Some(game.game_id) == self.selected {
+                write!(out, "{}[{}]{} ", style::Invert, game.title(), style::Reset)?;
+            } else {
+                write!(out, " {}  ", game.title())?;
+            }
+        }
+        write!(out, "\r\n\r\n")?;
+
+        let view = match self.view {
+            Some(ref view) => view,
+            None => return write!(out, "Waiting for games, q to quit\r\n"),
+        };
+        let state = if view.summary.live { "live" } else { "over" };
+        write!(out, "{}Game #{}{} ({}, round {})", style::Bold, view.summary.game_id, style::Reset, state, view.history.len())?;
+        if let Some(match_id) = view.summary.match_id {
+            write!(out, " in match {}", match_id)?;
+        }
+        write!(out, "\r\n")?;
+
+        for player in &view.state.players {
+            let team = player.team.map(|t| format!(" [{}]", t)).unwrap_or_default();
+            let alive = if player.alive { "alive" } else { "dead" };
+            write!(out, "  {:<20} ammo {:<6} {}\r\n", format!("{}{}", player.nickname, team), "*".repeat(player.ammo_bag as usize), alive)?;
+        }
+
+        write!(out, "\r\n{}History{}\r\n", style::Bold, style::Reset)?;
+        let (_, height) = termion::terminal_size().unwrap_or((80, 24));
+        let room = (height as usize).saturating_sub(12 + view.state.players.len());
+        let skip = view.history.len().saturating_sub(room);
+        for (i, moves) in view.history.iter().enumerate().skip(skip) {
+            let moves: Vec<String> = moves.iter().map(|m| format!("{:?}", m)).collect();
+            write!(out, "  {:>3}  {}\r\n", i + 1, moves.join("  "))?;
+        }
+
+        if let Some(result) = view.result() {
+            write!(out, "\r\n{}{}{}{}\r\n", style::Bold, color::Fg(color::Green), result, style::Reset)?;
+        }
+        write!(out, "\r\n<-/-> switch game  f live/finished  q quit\r\n")
+    }
+}
+
+/// `host:port`, an IPv6 address in brackets
+fn authority(target: &str, port: u16) -> String {
+    if target.contains(':') {
+        format!("[{}]:{}", target, port)
+    } else {
+        format!("{}:{}", target, port)
+    }
+}
+
+/// A minimal blocking `GET`, the connection is closed after every request
+fn get_json(target: &str, port: u16, path: &str) -> Result<Json, String> {
+    let socket = (target, port).to_socket_addrs().map_err(|e| e.to_string())?
+        .next().ok_or("no address")?;
+    let mut stream = TcpStream::connect_timeout(&socket, Duration::from_secs(2)).map_err(|e| e.to_string())?;
+    stream.set_read_timeout(Some(Duration::from_secs(2))).map_err(|e| e.to_string())?;
+    write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", path, authority(target, port)).map_err(|e| e.to_string())?;
+
+    let mut response = String::new();
+    let mut body = String::new();
+    let mut reader = io::BufReader::new(stream);
+    BufRead::read_line(&mut reader, &mut response).map_err(|e| e.to_string())?;
+    if !response.starts_with("HTTP/1.1 200") {
+        return Err(format!("{}: {}", path, response.trim()))
+    }
+
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        BufRead::read_line(&mut reader, &mut header).map_err(|e| e.to_string())?;
+        let header = header.trim();
+        if header.is_empty() {
+            break
+        }
+        let lower = header.to_lowercase();
+        if let Some(value) = lower.strip_prefix("content-length:") {
+            length = value.trim().parse::<u64>().ok();
+        }
+    }
+    let length = length.ok_or("missing Content-Length")?;
+    reader.take(length).read_to_string(&mut body).map_err(|e| e.to_string())?;
+    Json::from_str(&body).map_err(|e| e.to_string())
+}
+
+fn main() {
+    let args: Args = docopt::Docopt::new(USAGE).and_then(|d| d.decode())
+                                       .unwrap_or_else(|e| e.exit());
+
+    let mut spectator = Spectator {
+        target: args.flag_target.clone(),
+        port: args.flag_port,
+        show_finished: false,
+        games: Vec::new(),
+        selected: None,
+        view: None,
+        error: None,
+    };
+
+    let stdout = io::stdout().into_raw_mode().expect("the terminal does not support raw mode");
+    let mut screen = AlternateScreen::from(stdout);
+    write!(screen, "{}", cursor::Hide).unwrap();
+    let mut keys = termion::async_stdin().keys();
+    let refresh = Duration::from_millis(args.flag_refresh);
+    let mut last_refresh: Option<Instant> = None;
+
+    loop {
+        let mut dirty = false;
+        while let Some(Ok(key)) = keys.next() {
+            dirty = true;
+            match key {
+                Key::Char('q') | Key::Ctrl('c') | Key::Esc => {
+                    write!(screen, "{}", cursor::Show).unwrap();
+                    return
+                },
+                Key::Left | Key::Char('h') => spectator.select(-1),
+                Key::Right | Key::Char('l') => spectator.select(1),
+                Key::Char('f') => {
+                    spectator.show_finished = !spectator.show_finished;
+                    spectator.selected = None;
+                    spectator.view = None;
+                },
+                _ => dirty = false,
+            }
+        }
+
+        if dirty || last_refresh.map(|t| t.elapsed() >= refresh).unwrap_or(true) {
+            spectator.refresh();
+            last_refresh = Some(Instant::now());
+            spectator.render(&mut screen).unwrap();
+            screen.flush().unwrap();
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+}